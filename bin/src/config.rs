@@ -0,0 +1,410 @@
+use std::{
+  collections::HashSet,
+  fs,
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
+  thread,
+  time::Duration,
+};
+
+use clap::Parser;
+use log::{info, warn, LevelFilter};
+use media::{Interface, PortRange};
+use serde::Deserialize;
+
+const DEFAULT_LISTEN_NG: &str = "0.0.0.0:22222";
+const DEFAULT_PORT_MIN: usize = 10000;
+const DEFAULT_PORT_MAX: usize = 20000;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "RTP media relay controlled over the ng protocol")]
+pub struct Args {
+  /// TOML config file, the command line options override its values
+  #[arg(short, long)]
+  pub config: Option<PathBuf>,
+  /// Address the ng control server listens on, can be repeated
+  #[arg(long = "listen-ng")]
+  pub listen_ng: Vec<SocketAddr>,
+  /// Address the ng control server listens on over tcp, can be repeated
+  #[arg(long = "listen-tcp-ng")]
+  pub listen_tcp_ng: Vec<SocketAddr>,
+  /// Address of the http json and websocket control api, can be repeated
+  #[arg(long = "listen-http")]
+  pub listen_http: Vec<SocketAddr>,
+  /// Media interface as [NAME/]BIND[!ADVERTISED], can be repeated
+  #[arg(short, long = "interface")]
+  pub interfaces: Vec<String>,
+  /// Discover the public address of the interfaces that have nothing else to advertise
+  #[arg(long)]
+  pub public_ip_lookup: Option<bool>,
+  /// Lowest media port of the interfaces without their own range
+  #[arg(long)]
+  pub port_min: Option<usize>,
+  /// Highest media port of the interfaces without their own range
+  #[arg(long)]
+  pub port_max: Option<usize>,
+  /// Seconds without media before a call is ended
+  #[arg(long)]
+  pub timeout: Option<u64>,
+  /// Seconds without media before a call on hold is ended
+  #[arg(long)]
+  pub silent_timeout: Option<u64>,
+  /// Seconds after which a call is ended whatever its media
+  #[arg(long)]
+  pub final_timeout: Option<u64>,
+  /// Seconds a released port waits before being reused
+  #[arg(long)]
+  pub port_quarantine: Option<u64>,
+  /// Seconds the running calls are given to end after SIGTERM or SIGINT
+  #[arg(long)]
+  pub drain_timeout: Option<u64>,
+  /// Media workers tick, in milliseconds
+  #[arg(long)]
+  pub tick_ms: Option<u64>,
+  /// Number of media workers, one per core by default
+  #[arg(long)]
+  pub workers: Option<usize>,
+  /// Log level: off, error, warn, info, debug or trace
+  #[arg(long)]
+  pub log_level: Option<String>,
+  /// Add timestamps to the log lines
+  #[arg(long)]
+  pub log_timestamps: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+  pub listen_ng: Vec<SocketAddr>,
+  pub listen_tcp_ng: Vec<SocketAddr>,
+  pub listen_http: Vec<SocketAddr>,
+  pub interfaces: Vec<FileInterface>,
+  pub public_ip_lookup: Option<bool>,
+  pub port_min: Option<usize>,
+  pub port_max: Option<usize>,
+  pub timeout: Option<u64>,
+  pub silent_timeout: Option<u64>,
+  pub final_timeout: Option<u64>,
+  pub port_quarantine: Option<u64>,
+  pub drain_timeout: Option<u64>,
+  pub tick_ms: Option<u64>,
+  pub workers: Option<usize>,
+  pub log_level: Option<String>,
+  pub log_timestamps: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileInterface {
+  pub name: String,
+  pub bind: IpAddr,
+  pub advertised: Option<String>,
+  pub public_ip_lookup: Option<bool>,
+  pub port_min: Option<usize>,
+  pub port_max: Option<usize>,
+}
+
+pub struct InterfaceConfig {
+  pub name: String,
+  //local address the media sockets are bound to
+  pub bind: IpAddr,
+  //address put in the sdp, the bind address when not set
+  pub advertised: Option<String>,
+  //discover the public address when there is nothing else to advertise
+  pub public_ip_lookup: bool,
+  pub port_range: PortRange,
+}
+
+pub struct Settings {
+  pub listen_ng: Vec<SocketAddr>,
+  pub listen_tcp_ng: Vec<SocketAddr>,
+  pub listen_http: Vec<SocketAddr>,
+  pub interfaces: Vec<InterfaceConfig>,
+  pub timeout: Duration,
+  pub silent_timeout: Duration,
+  pub final_timeout: Option<Duration>,
+  pub port_quarantine: Duration,
+  pub drain_timeout: Duration,
+  pub tick: Duration,
+  pub workers: usize,
+  pub log_level: LevelFilter,
+  pub log_timestamps: bool,
+}
+
+impl FileConfig {
+  pub fn load(path: &PathBuf) -> Result<Self, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Cannot read config file {}: {}", path.display(), e))?;
+    toml::from_str(&content).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+  }
+}
+
+impl Settings {
+  // the command line wins over the config file, which wins over the defaults
+  pub fn load(args: Args) -> Result<Self, String> {
+    let file = match &args.config {
+      Some(path) => FileConfig::load(path)?,
+      None => FileConfig::default(),
+    };
+    let public_ip_lookup = args.public_ip_lookup.or(file.public_ip_lookup).unwrap_or(true);
+    let port_min = args.port_min.or(file.port_min).unwrap_or(DEFAULT_PORT_MIN);
+    let port_max = args.port_max.or(file.port_max).unwrap_or(DEFAULT_PORT_MAX);
+
+    let interfaces = if !args.interfaces.is_empty() {
+      args
+        .interfaces
+        .iter()
+        .map(|spec| {
+          InterfaceConfig::parse(
+            spec,
+            public_ip_lookup,
+            PortRange {
+              min: port_min,
+              max: port_max,
+            },
+          )
+        })
+        .collect::<Result<Vec<_>, _>>()?
+    } else if !file.interfaces.is_empty() {
+      file
+        .interfaces
+        .into_iter()
+        .map(|interface| InterfaceConfig {
+          name: interface.name,
+          bind: interface.bind,
+          advertised: interface.advertised,
+          public_ip_lookup: interface.public_ip_lookup.unwrap_or(public_ip_lookup),
+          port_range: PortRange {
+            min: interface.port_min.unwrap_or(port_min),
+            max: interface.port_max.unwrap_or(port_max),
+          },
+        })
+        .collect()
+    } else {
+      vec![InterfaceConfig {
+        name: "default".to_string(),
+        bind: IpAddr::from([0, 0, 0, 0]),
+        advertised: None,
+        public_ip_lookup,
+        port_range: PortRange {
+          min: port_min,
+          max: port_max,
+        },
+      }]
+    };
+
+    let listen_ng = match (args.listen_ng.is_empty(), file.listen_ng.is_empty()) {
+      (false, _) => args.listen_ng,
+      (true, false) => file.listen_ng,
+      (true, true) => vec![DEFAULT_LISTEN_NG.parse().expect("valid default address")],
+    };
+    let listen_tcp_ng = if args.listen_tcp_ng.is_empty() {
+      file.listen_tcp_ng
+    } else {
+      args.listen_tcp_ng
+    };
+    let listen_http = if args.listen_http.is_empty() {
+      file.listen_http
+    } else {
+      args.listen_http
+    };
+    let log_level = args.log_level.or(file.log_level).unwrap_or("debug".to_string());
+
+    let settings = Self {
+      listen_ng,
+      listen_tcp_ng,
+      listen_http,
+      interfaces,
+      timeout: Duration::from_secs(args.timeout.or(file.timeout).unwrap_or(60)),
+      silent_timeout: Duration::from_secs(args.silent_timeout.or(file.silent_timeout).unwrap_or(3600)),
+      final_timeout: args.final_timeout.or(file.final_timeout).map(Duration::from_secs),
+      port_quarantine: Duration::from_secs(args.port_quarantine.or(file.port_quarantine).unwrap_or(10)),
+      drain_timeout: Duration::from_secs(args.drain_timeout.or(file.drain_timeout).unwrap_or(60)),
+      tick: Duration::from_millis(args.tick_ms.or(file.tick_ms).unwrap_or(10)),
+      workers: args
+        .workers
+        .or(file.workers)
+        .unwrap_or(thread::available_parallelism().map_or(1, |workers| workers.get())),
+      log_level: log_level
+        .parse()
+        .map_err(|_| format!("Invalid log level {}", log_level))?,
+      log_timestamps: args.log_timestamps.or(file.log_timestamps).unwrap_or(true),
+    };
+    settings.validate()?;
+    Ok(settings)
+  }
+
+  fn validate(&self) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for interface in &self.interfaces {
+      if interface.name.is_empty() {
+        return Err("Interface name can't be empty".to_string());
+      }
+      if !names.insert(interface.name.as_str()) {
+        return Err(format!("Duplicated interface {}", interface.name));
+      }
+      let range = &interface.port_range;
+      if range.max > u16::MAX as usize {
+        return Err(format!(
+          "Interface {}: port max {} is above {}",
+          interface.name,
+          range.max,
+          u16::MAX
+        ));
+      }
+      // every worker needs at least one even rtp port with its rtcp port
+      let worker_range = range.split(self.workers, 0);
+      if range.min == 0 || worker_range.min + 2 > worker_range.max {
+        return Err(format!(
+          "Interface {}: port range {}-{} holds no rtp/rtcp pair for each of the {} workers",
+          interface.name, range.min, range.max, self.workers
+        ));
+      }
+    }
+    if self.timeout.is_zero() || self.silent_timeout.is_zero() || self.final_timeout.is_some_and(|t| t.is_zero()) {
+      return Err("Timeouts must be greater than zero".to_string());
+    }
+    if self.tick.is_zero() {
+      return Err("Tick must be greater than zero".to_string());
+    }
+    if self.workers == 0 {
+      return Err("At least one media worker is needed".to_string());
+    }
+    Ok(())
+  }
+
+  // each worker gets its own share of the port range of every interface
+  pub fn media_config(&self, interfaces: &[Interface], worker: usize) -> media::Config {
+    media::Config {
+      interfaces: interfaces
+        .iter()
+        .map(|interface| Interface {
+          port_range: interface.port_range.split(self.workers, worker),
+          ..interface.clone()
+        })
+        .collect(),
+      timeout: self.timeout,
+      silent_timeout: self.silent_timeout,
+      final_timeout: self.final_timeout,
+      port_quarantine: self.port_quarantine,
+    }
+  }
+}
+
+impl InterfaceConfig {
+  // [NAME/]BIND[!ADVERTISED], the name defaults to "default"
+  fn parse(spec: &str, public_ip_lookup: bool, port_range: PortRange) -> Result<Self, String> {
+    let (name, addrs) = spec.split_once('/').unwrap_or(("default", spec));
+    let (bind, advertised) = match addrs.split_once('!') {
+      Some((bind, advertised)) => (bind, Some(advertised.to_string())),
+      None => (addrs, None),
+    };
+    Ok(Self {
+      name: name.to_string(),
+      bind: bind
+        .parse()
+        .map_err(|_| format!("Invalid bind address {} in interface {}", bind, spec))?,
+      advertised,
+      public_ip_lookup,
+      port_range,
+    })
+  }
+
+  // explicit address first, then a specific bind address, then the public ip lookup
+  pub async fn resolve(self) -> Result<Interface, String> {
+    let advertised = match self.advertised {
+      Some(advertised) => Some(advertised),
+      None if !self.bind.is_unspecified() => Some(self.bind.to_string()),
+      None if self.public_ip_lookup => match public_ip_address::perform_lookup(None).await {
+        Ok(res) => {
+          info!("interface {} advertises public address {}", self.name, res.ip);
+          Some(res.ip.to_string())
+        }
+        Err(e) => {
+          warn!("public ip lookup failed: {}", e);
+          None
+        }
+      },
+      None => None,
+    };
+    let advertised = advertised.ok_or(format!(
+      "No address to advertise for interface {}, set one explicitly",
+      self.name
+    ))?;
+    Ok(Interface {
+      name: self.name,
+      bind: self.bind,
+      advertised,
+      port_range: self.port_range,
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use clap::Parser;
+
+  use super::{Args, FileConfig, InterfaceConfig, PortRange, Settings};
+
+  #[test]
+  fn parse_interface_spec() {
+    let range = || PortRange { min: 10000, max: 20000 };
+    let interface = InterfaceConfig::parse("internal/10.0.0.1!203.0.113.1", true, range()).unwrap();
+    assert_eq!(interface.name, "internal");
+    assert_eq!(interface.bind.to_string(), "10.0.0.1");
+    assert_eq!(interface.advertised.as_deref(), Some("203.0.113.1"));
+
+    let interface = InterfaceConfig::parse("0.0.0.0", true, range()).unwrap();
+    assert_eq!(interface.name, "default");
+    assert_eq!(interface.advertised, None);
+
+    assert!(InterfaceConfig::parse("internal/not-an-ip", true, range()).is_err());
+  }
+
+  #[test]
+  fn parse_config_file() {
+    let file: FileConfig = toml::from_str(
+      r#"
+      listen_ng = ["127.0.0.1:22222"]
+      timeout = 30
+
+      [[interfaces]]
+      name = "external"
+      bind = "0.0.0.0"
+      advertised = "203.0.113.1"
+      port_min = 30000
+      port_max = 30100
+      "#,
+    )
+    .unwrap();
+    assert_eq!(file.listen_ng.len(), 1);
+    assert_eq!(file.timeout, Some(30));
+    assert_eq!(file.interfaces[0].port_min, Some(30000));
+
+    assert!(toml::from_str::<FileConfig>("unknown = 1").is_err());
+  }
+
+  #[test]
+  fn cli_overrides_defaults() {
+    let args = Args::parse_from([
+      "bin", "--interface", "10.0.0.1", "--port-min", "40000", "--port-max", "40100", "--timeout", "5",
+    ]);
+    let settings = Settings::load(args).unwrap();
+    assert_eq!(settings.interfaces[0].port_range.min, 40000);
+    assert_eq!(settings.interfaces[0].port_range.max, 40100);
+    assert_eq!(settings.timeout.as_secs(), 5);
+    assert_eq!(settings.listen_ng[0].to_string(), "0.0.0.0:22222");
+  }
+
+  #[test]
+  fn reject_invalid_settings() {
+    let load = |args: &[&str]| Settings::load(Args::parse_from([&["bin"], args].concat()));
+    assert!(load(&["--workers", "1", "--port-min", "20000", "--port-max", "20001"]).is_err());
+    assert!(load(&["--port-max", "70000"]).is_err());
+    assert!(load(&["--interface", "a/0.0.0.0", "--interface", "a/::"]).is_err());
+    assert!(load(&["--timeout", "0"]).is_err());
+    assert!(load(&["--log-level", "loud"]).is_err());
+    assert!(load(&["--workers", "0"]).is_err());
+    assert!(load(&["--workers", "2", "--port-min", "20000", "--port-max", "20002"]).is_err());
+    assert!(load(&["--workers", "1", "--port-min", "20000", "--port-max", "20002"]).is_ok());
+  }
+}
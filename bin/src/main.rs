@@ -1,68 +1,238 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Instant,
+};
 
-use log::debug;
+use clap::Parser;
+use config::{Args, Settings};
+use log::{debug, error, info, warn};
 use media::{
-  ChannelId, Config, ExtInput, ExtOut, MediaRpcRequest, MediaRpcResponse, OwnerType, PortRange, Rpc,
+  ChannelId, ExtInput, ExtOut, MediaRpcCmd, MediaRpcRequest, MediaRpcResponse, MediaRpcResult, OwnerType, Rpc,
   RtpEngineMediaWorker, RtpEvent, SCfg,
 };
-use ng_control::NgControlServer;
+use ng_control::{NgControlServer, NgHttpControlServer, NgTcpControlServer};
 use sans_io_runtime::{backend::PollingBackend, Controller};
+use signal_hook::{
+  consts::{SIGINT, SIGTERM},
+  flag,
+};
 use tokio::{
   sync::{mpsc, oneshot},
   task,
 };
 
+mod config;
+
+//list answers collected until every worker replied
+struct PendingList {
+  remaining: usize,
+  limit: Option<usize>,
+  call_ids: Vec<String>,
+}
+
+// the requester may be gone already, e.g. an http client that disconnected
+fn answer_rpc(answers: &mut HashMap<String, oneshot::Sender<MediaRpcResponse>>, rpc: MediaRpcResponse) {
+  if let Some(tx) = answers.remove(&rpc.id) {
+    debug!("rpc answer: {:?}", rpc);
+    if let Err(rpc) = tx.send(rpc) {
+      debug!("requester of rpc {} is gone, answer dropped", rpc.id);
+    }
+  }
+}
+
+// the workers answer nothing once they are shutting down, the requests arriving then are refused
+fn refuse_rpc(rpc: Rpc<MediaRpcRequest, MediaRpcResponse>) {
+  let res = MediaRpcResponse {
+    id: rpc.req.id,
+    res: MediaRpcResult::Error("Shutting down".to_string()),
+  };
+  if let Err(res) = rpc.answer_tx.send(res) {
+    debug!("requester of rpc {} is gone, answer dropped", res.id);
+  }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), ()> {
-  env_logger::builder()
-    .filter_level(log::LevelFilter::Debug)
-    .format_timestamp_millis()
-    .init();
-  let ip_lookup_res = public_ip_address::perform_lookup(None).await.unwrap();
+  let mut settings = match Settings::load(Args::parse()) {
+    Ok(settings) => settings,
+    Err(e) => {
+      eprintln!("{}", e);
+      return Err(());
+    }
+  };
+  let mut logger = env_logger::builder();
+  logger.filter_level(settings.log_level);
+  if settings.log_timestamps {
+    logger.format_timestamp_millis();
+  } else {
+    logger.format_timestamp(None);
+  }
+  logger.init();
+
+  let mut interfaces = Vec::with_capacity(settings.interfaces.len());
+  for interface in settings.interfaces.drain(..) {
+    match interface.resolve().await {
+      Ok(interface) => interfaces.push(interface),
+      Err(e) => {
+        error!("{}", e);
+        return Err(());
+      }
+    }
+  }
   let mut rpc_answer_mapper = HashMap::<String, oneshot::Sender<MediaRpcResponse>>::new();
   let (rpc_sender, mut rpc_recv) = mpsc::channel::<Rpc<MediaRpcRequest, MediaRpcResponse>>(1024);
-  let mut ng_server = NgControlServer::new("0.0.0.0:22222".to_string(), rpc_sender);
 
   let mut controller = Controller::<ExtInput, ExtOut, SCfg, ChannelId, RtpEvent, 128>::default();
-  controller.add_worker::<OwnerType, _, RtpEngineMediaWorker, PollingBackend<_, 128, 512>>(
-    Duration::from_millis(10),
-    Config {
-      ip: ip_lookup_res.ip.to_string(),
-      port_range: PortRange { min: 10000, max: 20000 },
-    },
-    None,
-  );
-
-  tokio::spawn(async move {
-    ng_server.process().await;
-  });
+  for worker in 0..settings.workers {
+    controller.add_worker::<OwnerType, _, RtpEngineMediaWorker, PollingBackend<_, 128, 512>>(
+      settings.tick,
+      settings.media_config(&interfaces, worker),
+      None,
+    );
+  }
+  let mut pending_lists = HashMap::<String, PendingList>::new();
+
+  // the first signal starts the drain, a second one exits right away
+  let term = Arc::new(AtomicBool::new(false));
+  for signal in [SIGTERM, SIGINT] {
+    let registered =
+      flag::register_conditional_shutdown(signal, 1, term.clone()).and_then(|_| flag::register(signal, term.clone()));
+    if let Err(e) = registered {
+      error!("cannot handle signal {}: {}", signal, e);
+      return Err(());
+    }
+  }
+  let mut drain_deadline: Option<Instant> = None;
+  let mut drained_workers = 0;
+  let mut shutting_down = false;
+
+  for addr in &settings.listen_ng {
+    let mut ng_server = match NgControlServer::bind(*addr, rpc_sender.clone()).await {
+      Ok(ng_server) => ng_server,
+      Err(e) => {
+        error!("cannot listen for ng on udp {}: {}", addr, e);
+        return Err(());
+      }
+    };
+    tokio::spawn(async move {
+      ng_server.process().await;
+    });
+  }
+  for addr in &settings.listen_tcp_ng {
+    let mut ng_server = match NgTcpControlServer::bind(*addr, rpc_sender.clone()).await {
+      Ok(ng_server) => ng_server,
+      Err(e) => {
+        error!("cannot listen for ng on tcp {}: {}", addr, e);
+        return Err(());
+      }
+    };
+    tokio::spawn(async move {
+      ng_server.process().await;
+    });
+  }
+  for addr in &settings.listen_http {
+    let mut http_server = match NgHttpControlServer::bind(*addr, rpc_sender.clone()).await {
+      Ok(http_server) => http_server,
+      Err(e) => {
+        error!("cannot listen for http on {}: {}", addr, e);
+        return Err(());
+      }
+    };
+    tokio::spawn(async move {
+      http_server.process().await;
+    });
+  }
 
   let local = task::LocalSet::new();
   local
     .run_until(async move {
       loop {
         tokio::select! {
-          _ = tokio::time::sleep(Duration::from_millis(10)) => {
+          _ = tokio::time::sleep(settings.tick) => {
             if controller.process().is_none() {
               break;
             }
 
+            match drain_deadline {
+              None if term.load(Ordering::Relaxed) => {
+                info!("draining the running calls for at most {:?}", settings.drain_timeout);
+                drain_deadline = Some(Instant::now() + settings.drain_timeout);
+                for worker in 0..settings.workers {
+                  controller.send_to(worker as u16, ExtInput::Drain);
+                }
+              }
+              Some(deadline) if !shutting_down && (drained_workers == settings.workers || deadline <= Instant::now()) => {
+                if drained_workers < settings.workers {
+                  warn!("drain deadline passed, ending the remaining calls");
+                }
+                controller.shutdown();
+                shutting_down = true;
+              }
+              _ => {}
+            }
+
             while let Some(ext) = controller.pop_event() {
               match ext {
-                ExtOut::Rpc(rpc) => {
-                  if let Some(tx) = rpc_answer_mapper.remove(&rpc.id) {
-                    debug!("rpc answer: {:?}", rpc);
-                    tx.send(rpc).unwrap();
+                ExtOut::Rpc(mut rpc) => {
+                  // a list is answered once every worker sent its calls
+                  if let (Some(pending), MediaRpcResult::List(call_ids)) = (pending_lists.get_mut(&rpc.id), &mut rpc.res) {
+                    pending.call_ids.append(call_ids);
+                    pending.remaining -= 1;
+                    if pending.remaining > 0 {
+                      continue;
+                    }
+                    let mut pending = pending_lists.remove(&rpc.id).expect("pending list");
+                    pending.call_ids.sort();
+                    if let Some(limit) = pending.limit {
+                      pending.call_ids.truncate(limit);
+                    }
+                    rpc.res = MediaRpcResult::List(pending.call_ids);
                   }
+                  answer_rpc(&mut rpc_answer_mapper, rpc);
+                }
+                ExtOut::CallEnded(call_id, reason) => {
+                  info!("call {} ended by {:?}", call_id, reason);
+                }
+                ExtOut::Drained(worker) => {
+                  info!("worker {} has no call left", worker);
+                  drained_workers += 1;
                 }
               }
             }
           }
           Some(rpc) = rpc_recv.recv() => {
-            println!("got a rpc: {:?}", rpc.req);
-            let req = rpc.req;
+            debug!("got a rpc: {:?}", rpc.req);
+            if shutting_down {
+              refuse_rpc(rpc);
+              continue;
+            }
+            let req = Box::new(rpc.req);
             rpc_answer_mapper.insert(req.id.clone(), rpc.answer_tx);
-            controller.send_to_best(ExtInput::Rpc(req));
+            // every command of a call goes to the worker owning it
+            match (req.cmd.call_id(), &req.cmd) {
+              (Some(call_id), _) => {
+                let worker = RtpEngineMediaWorker::worker_for_call(call_id, settings.workers);
+                controller.send_to(worker, ExtInput::Rpc(req));
+              }
+              (None, MediaRpcCmd::List(limit)) => {
+                pending_lists.insert(
+                  req.id.clone(),
+                  PendingList {
+                    remaining: settings.workers,
+                    limit: *limit,
+                    call_ids: vec![],
+                  },
+                );
+                for worker in 0..settings.workers {
+                  controller.send_to(worker as u16, ExtInput::Rpc(req.clone()));
+                }
+              }
+              (None, _) => controller.send_to_best(ExtInput::Rpc(req)),
+            }
           }
           else => {
             break;
@@ -74,3 +244,42 @@ async fn main() -> Result<(), ()> {
 
   Ok(())
 }
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashMap;
+
+  use media::{MediaRpcCmd, MediaRpcRequest, MediaRpcResponse, MediaRpcResult, Rpc};
+  use tokio::sync::oneshot;
+
+  use super::{answer_rpc, refuse_rpc};
+
+  #[test]
+  fn answer_dropped_requester() {
+    let mut answers = HashMap::new();
+    let (tx, rx) = oneshot::channel();
+    answers.insert("rpc-1".to_string(), tx);
+    drop(rx);
+    answer_rpc(
+      &mut answers,
+      MediaRpcResponse {
+        id: "rpc-1".to_string(),
+        res: MediaRpcResult::Pong,
+      },
+    );
+    assert!(answers.is_empty());
+  }
+
+  #[test]
+  fn refused_while_shutting_down() {
+    let (rpc, mut rx) = Rpc::new(MediaRpcRequest {
+      id: "rpc-1".to_string(),
+      cmd: MediaRpcCmd::Ping,
+    });
+    refuse_rpc(rpc);
+    assert_eq!(
+      rx.try_recv().map(|res| res.res),
+      Ok(MediaRpcResult::Error("Shutting down".to_string()))
+    );
+  }
+}
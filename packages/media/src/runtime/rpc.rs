@@ -1,24 +1,225 @@
+use std::net::IpAddr;
+
 use async_trait::async_trait;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MediaRpcCmd {
   Ping,
-  //call_id, leg_id, sdp
-  Call(String, String, String),
+  Offer(SdpExchange),
+  Answer(SdpExchange),
+  End(EndRequest),
   //call_id
-  End(String),
+  Query(String),
+  //limit
+  List(Option<usize>),
+}
+
+impl MediaRpcCmd {
+  //the call the command is about, None for the commands not bound to a call
+  pub fn call_id(&self) -> Option<&str> {
+    match self {
+      MediaRpcCmd::Offer(req) | MediaRpcCmd::Answer(req) => Some(&req.call_id),
+      MediaRpcCmd::End(req) => Some(&req.call_id),
+      MediaRpcCmd::Query(call_id) => Some(call_id),
+      MediaRpcCmd::Ping | MediaRpcCmd::List(_) => None,
+    }
+  }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MediaRpcResult {
   Pong,
   //sdp
-  Call(String),
+  Offer(String),
+  //sdp
+  Answer(String),
   End,
+  Query(CallStats),
+  //call_ids
+  List(Vec<String>),
   //reason_error
   Error(String),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SdpExchange {
+  pub call_id: String,
+  pub from_tag: String,
+  pub to_tag: Option<String>,
+  pub sdp: String,
+  pub rtcp_mux: Vec<RtcpMux>,
+  //address family of the side the sdp is sent to, same as the sender when not set
+  pub address_family: Option<AddressFamily>,
+  //interface of the side sending the sdp
+  pub from_interface: Option<String>,
+  //interface of the side the sdp is sent to
+  pub to_interface: Option<String>,
+  pub options: SdpOptions,
+}
+
+//options of an offer or an answer, the ones not given are left to the engine defaults
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SdpOptions {
+  pub flags: SdpFlags,
+  pub replace: Vec<Replace>,
+  pub ice: Option<IceOption>,
+  pub transport_protocol: Option<TransportProtocol>,
+  pub codec: CodecOptions,
+  pub sdes: Vec<SdesOption>,
+  pub dtls: Option<DtlsOption>,
+  pub label: Option<String>,
+  pub via_branch: Option<String>,
+  //address the signalling was received from
+  pub received_from: Option<IpAddr>,
+  //overrides the media address of the sdp
+  pub media_address: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SdpFlags {
+  //use the addresses of the sdp even when behind nat
+  pub trust_address: bool,
+  pub symmetric: bool,
+  pub asymmetric: bool,
+  //drop the packets not coming from the learned remote address
+  pub strict_source: bool,
+  //follow the remote endpoint when its source address changes
+  pub media_handover: bool,
+  pub port_latching: bool,
+  pub record_call: bool,
+  pub loop_protect: bool,
+  //use the address the signalling came from instead of the sdp one
+  pub sip_source_address: bool,
+  pub no_rtcp_attribute: bool,
+  pub full_rtcp_attribute: bool,
+  pub original_sendrecv: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replace {
+  //o= address is the engine one, username and session id stay those first sent to the endpoint
+  Origin,
+  //the session c= line is always the engine address, kept for compatibility
+  SessionConnection,
+  //o= version only increases when the description changes
+  SdpVersion,
+  //o= username is "-"
+  Username,
+  //s= is "-"
+  SessionName,
+  ZeroAddress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IceOption {
+  Remove,
+  Force,
+  Default,
+  ForceRelay,
+  Optional,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportProtocol {
+  RtpAvp,
+  RtpSavp,
+  RtpAvpf,
+  RtpSavpf,
+  UdpTlsRtpSavp,
+  UdpTlsRtpSavpf,
+  //keep the protocol of the offer when answering
+  Accept,
+}
+
+//codec names, as in the rtpmap attributes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CodecOptions {
+  pub strip: Vec<String>,
+  pub offer: Vec<String>,
+  pub transcode: Vec<String>,
+  pub mask: Vec<String>,
+  pub accept: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SdesOption {
+  Off,
+  //crypto suite not to offer
+  No(String),
+  //only crypto suite to offer
+  Only(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtlsOption {
+  Off,
+  Passive,
+  Active,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+  Ipv4,
+  Ipv6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcpMux {
+  //offer rtcp-mux to the callee even when the caller did not
+  Offer,
+  //accept rtcp-mux from the caller whatever the callee answers
+  Accept,
+  //strip rtcp-mux from the offer sent to the callee, accepting it for the caller
+  Demux,
+  //never accept rtcp-mux from the caller
+  Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndRequest {
+  pub call_id: String,
+  pub from_tag: Option<String>,
+  //only the branch with this tag is removed when set
+  pub to_tag: Option<String>,
+  //seconds media keeps flowing before the teardown
+  pub delay: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCounters {
+  pub packets: u64,
+  pub bytes: u64,
+  pub errors: u64,
+}
+
+impl std::ops::AddAssign for TrafficCounters {
+  fn add_assign(&mut self, other: Self) {
+    self.packets += other.packets;
+    self.bytes += other.bytes;
+    self.errors += other.errors;
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LegStats {
+  pub tag: String,
+  //unix timestamp in seconds
+  pub created: u64,
+  pub local_addr: String,
+  pub remote_addr: String,
+  //traffic received from the remote endpoint of this leg
+  pub ingress: TrafficCounters,
+  //traffic sent to the remote endpoint of this leg
+  pub egress: TrafficCounters,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallStats {
+  //unix timestamp in seconds
+  pub created: u64,
+  pub legs: Vec<LegStats>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MediaRpcRequest {
   pub id: String,
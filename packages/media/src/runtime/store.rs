@@ -1,107 +1,654 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+  collections::{HashMap, VecDeque},
+  net::SocketAddr,
+  time::{Duration, Instant},
+};
 
 use log::debug;
-use sans_io_runtime::backend;
+
+use crate::{CallStats, LegStats, RtcpMux, TrafficCounters};
 
 use super::worker::{PortRange, TaskId};
 
-//TODO: refactor store to save data of legs and call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+  Rtp,
+  Rtcp,
+}
+
+pub struct MediaStream {
+  pub task_id: TaskId,
+  //rtp port, the rtcp port is the next one
+  pub port: usize,
+  pub slot: Option<usize>,
+  pub rtcp_slot: Option<usize>,
+  //rtcp is sent to and received from the remote on the rtp port
+  pub rtcp_mux: bool,
+  pub local_addr: String,
+  pub remote_addr: String,
+  pub ingress: TrafficCounters,
+  pub egress: TrafficCounters,
+  //nothing received from the remote within the idle timeout
+  pub idle: bool,
+}
+
+pub struct Leg {
+  pub tag: String,
+  //tag and remote sdp of the forked branches answered through this leg before the current one
+  pub branches: Vec<(String, String)>,
+  //unix timestamp in seconds
+  pub created: u64,
+  pub remote_sdp: String,
+  pub local_sdp: String,
+  //index of the interface the streams of the leg are bound to
+  pub interface: usize,
+  //rtcp-mux flags of the last offer from this leg, applied when it is answered
+  pub rtcp_mux_flags: Vec<RtcpMux>,
+  pub streams: Vec<MediaStream>,
+}
+
+impl Leg {
+  // the addresses are those of the first m-line, the counters add up every stream
+  pub fn stats(&self) -> LegStats {
+    let stream = self.streams.first();
+    let mut ingress = TrafficCounters::default();
+    let mut egress = TrafficCounters::default();
+    for stream in self.streams.iter() {
+      ingress += stream.ingress;
+      egress += stream.egress;
+    }
+    LegStats {
+      tag: self.tag.clone(),
+      created: self.created,
+      local_addr: stream.map(|s| s.local_addr.clone()).unwrap_or_default(),
+      remote_addr: stream.map(|s| s.remote_addr.clone()).unwrap_or_default(),
+      ingress,
+      egress,
+    }
+  }
+}
+
+pub struct Call {
+  pub call_id: String,
+  //unix timestamp in seconds
+  pub created: u64,
+  //one of the legs put the media on hold, the silent timeout applies
+  pub on_hold: bool,
+  //idle timeout the streams run with, None until the call is answered
+  pub idle_timeout: Option<Duration>,
+  //the call is ended at this time whatever its activity
+  pub final_deadline: Option<Instant>,
+  pub legs: HashMap<String, Leg>,
+}
+
+impl Call {
+  pub fn stats(&self) -> CallStats {
+    let mut legs: Vec<LegStats> = self.legs.values().map(|leg| leg.stats()).collect();
+    legs.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.tag.cmp(&b.tag)));
+    CallStats {
+      created: self.created,
+      legs,
+    }
+  }
+}
+
+//where a task lives inside the call tree: call, leg tag, stream index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamKey {
+  pub call_id: u64,
+  pub tag: String,
+  pub index: usize,
+}
+
+struct PortPool {
+  ports: VecDeque<usize>,
+  //released ports with the time they can be reused
+  quarantined: VecDeque<(Instant, usize)>,
+}
+
 pub struct CallMediaStore {
-  port_pool: VecDeque<usize>,
-  addr_task_mapper: HashMap<String, TaskId>,
-  addr_backend_mapper: HashMap<String, usize>,
-  task_addr_mapper: HashMap<TaskId, String>,
-  addr_leg_task: HashMap<String, TaskId>,
-  task_slot: HashMap<TaskId, usize>,
-  calls: HashMap<u64, Vec<TaskId>>,
+  //one pool per interface
+  port_pools: Vec<PortPool>,
+  port_quarantine: Duration,
+  calls: HashMap<u64, Call>,
+  task_streams: HashMap<TaskId, StreamKey>,
+  slot_tasks: HashMap<usize, (TaskId, SocketKind)>,
+  listening: HashMap<SocketAddr, (TaskId, SocketKind)>,
 }
 
 impl CallMediaStore {
-  pub fn new(port_range: PortRange) -> Self {
+  pub fn new(port_ranges: &[PortRange], port_quarantine: Duration) -> Self {
     Self {
-      port_pool: (port_range.min..port_range.max).collect(),
-      addr_backend_mapper: HashMap::new(),
-      addr_task_mapper: HashMap::new(),
-      task_addr_mapper: HashMap::new(),
-      addr_leg_task: HashMap::new(),
-      task_slot: HashMap::new(),
+      port_pools: port_ranges
+        .iter()
+        .map(|port_range| PortPool {
+          // even rtp ports only, each one with the next odd port for rtcp (RFC 3550)
+          ports: (port_range.min + port_range.min % 2..port_range.max.saturating_sub(1))
+            .step_by(2)
+            .collect(),
+          quarantined: VecDeque::new(),
+        })
+        .collect(),
+      port_quarantine,
       calls: HashMap::new(),
+      task_streams: HashMap::new(),
+      slot_tasks: HashMap::new(),
+      listening: HashMap::new(),
     }
   }
 
-  pub fn next_port(&mut self) -> Option<usize> {
-    self.port_pool.pop_front()
+  pub fn next_port(&mut self, interface: usize) -> Option<usize> {
+    self.port_pools.get_mut(interface)?.ports.pop_front()
   }
 
-  pub fn push_port(&mut self, port: usize) {
-    self.port_pool.push_back(port)
+  pub fn push_port(&mut self, interface: usize, port: usize) {
+    if let Some(pool) = self.port_pools.get_mut(interface) {
+      pool.ports.push_back(port)
+    }
   }
 
-  pub fn add_task(&mut self, addr: String, task_id: TaskId) {
-    debug!("add task {:?} with addr {} ", task_id, addr);
-    self.task_addr_mapper.insert(task_id, addr.clone());
-    self.addr_task_mapper.insert(addr, task_id);
+  pub fn release_port(&mut self, now: Instant, interface: usize, port: usize) {
+    if let Some(pool) = self.port_pools.get_mut(interface) {
+      pool.quarantined.push_back((now + self.port_quarantine, port));
+    }
   }
 
-  pub fn remove_task(&mut self, task_id: &TaskId) {
-    if let Some(addr) = self.task_addr_mapper.remove(task_id) {
-      self.addr_task_mapper.remove(&addr);
+  pub fn reclaim_ports(&mut self, now: Instant) {
+    for pool in self.port_pools.iter_mut() {
+      while let Some((at, port)) = pool.quarantined.front().copied() {
+        if at > now {
+          break;
+        }
+        pool.quarantined.pop_front();
+        pool.ports.push_back(port);
+      }
     }
   }
 
-  pub fn add_call(&mut self, call_id: u64, task_id: TaskId) {
-    if let Some(tasks) = self.calls.get_mut(&call_id) {
-      tasks.push(task_id);
-    } else {
-      self.calls.insert(call_id, vec![task_id]);
+  pub fn get_call(&self, call_id: u64) -> Option<&Call> {
+    self.calls.get(&call_id)
+  }
+
+  pub fn get_call_mut(&mut self, call_id: u64) -> Option<&mut Call> {
+    self.calls.get_mut(&call_id)
+  }
+
+  pub fn get_leg(&self, call_id: u64, tag: &str) -> Option<&Leg> {
+    self.calls.get(&call_id)?.legs.get(tag)
+  }
+
+  pub fn get_leg_mut(&mut self, call_id: u64, tag: &str) -> Option<&mut Leg> {
+    self.calls.get_mut(&call_id)?.legs.get_mut(tag)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.calls.is_empty()
+  }
+
+  pub fn list_calls(&self, limit: Option<usize>) -> Vec<String> {
+    let mut call_ids: Vec<String> = self.calls.values().map(|call| call.call_id.clone()).collect();
+    call_ids.sort();
+    if let Some(limit) = limit {
+      call_ids.truncate(limit);
     }
+    call_ids
   }
 
-  pub fn get_call(&self, call_id: u64) -> Option<&Vec<TaskId>> {
-    self.calls.get(&call_id)
+  //insert a leg into the call, creating the call when it is the first leg.
+  pub fn add_leg(&mut self, call_id: u64, call_id_str: &str, leg: Leg) {
+    debug!("add leg {} to call {}", leg.tag, call_id_str);
+    let created = leg.created;
+    let call = self.calls.entry(call_id).or_insert_with(|| Call {
+      call_id: call_id_str.to_string(),
+      created,
+      on_hold: false,
+      idle_timeout: None,
+      final_deadline: None,
+      legs: HashMap::new(),
+    });
+    for (index, stream) in leg.streams.iter().enumerate() {
+      self.task_streams.insert(
+        stream.task_id,
+        StreamKey {
+          call_id,
+          tag: leg.tag.clone(),
+          index,
+        },
+      );
+    }
+    call.legs.insert(leg.tag.clone(), leg);
+  }
+
+  //append a stream to a leg, for an m-line added by a re-offer
+  pub fn add_stream(&mut self, call_id: u64, tag: &str, stream: MediaStream) -> bool {
+    let leg = match self.calls.get_mut(&call_id).and_then(|call| call.legs.get_mut(tag)) {
+      Some(leg) => leg,
+      None => return false,
+    };
+    self.task_streams.insert(
+      stream.task_id,
+      StreamKey {
+        call_id,
+        tag: tag.to_string(),
+        index: leg.streams.len(),
+      },
+    );
+    leg.streams.push(stream);
+    true
+  }
+
+  //move a leg to a new tag, used when the answer names the side the offer created or a new branch answers
+  pub fn rename_leg(&mut self, call_id: u64, tag: &str, new_tag: &str) -> bool {
+    if !self.rekey_leg(call_id, tag, new_tag) {
+      return false;
+    }
+    if let Some(leg) = self.get_leg_mut(call_id, new_tag) {
+      leg.branches.retain(|(branch, _)| branch != new_tag);
+      if !tag.is_empty() {
+        let remote_sdp = leg.remote_sdp.clone();
+        leg.branches.push((tag.to_string(), remote_sdp));
+      }
+    }
+    true
+  }
+
+  //forget a branch tag, when it is the current tag of a leg the leg goes back to its previous branch
+  //and the remote sdp of that branch. returns the tag of the leg, None when the tag is unknown or is
+  //the only tag of its leg
+  pub fn remove_branch(&mut self, call_id: u64, tag: &str) -> Option<String> {
+    let call = self.calls.get_mut(&call_id)?;
+    if let Some(leg) = call
+      .legs
+      .values_mut()
+      .find(|leg| leg.branches.iter().any(|(branch, _)| branch == tag))
+    {
+      leg.branches.retain(|(branch, _)| branch != tag);
+      return Some(leg.tag.clone());
+    }
+    let (previous, remote_sdp) = call.legs.get_mut(tag).and_then(|leg| leg.branches.pop())?;
+    if !self.rekey_leg(call_id, tag, &previous) {
+      return None;
+    }
+    let leg = self.get_leg_mut(call_id, &previous)?;
+    leg.remote_sdp = remote_sdp;
+    Some(previous)
+  }
+
+  fn rekey_leg(&mut self, call_id: u64, tag: &str, new_tag: &str) -> bool {
+    let call = match self.calls.get_mut(&call_id) {
+      Some(call) => call,
+      None => return false,
+    };
+    let mut leg = match call.legs.remove(tag) {
+      Some(leg) => leg,
+      None => return false,
+    };
+    leg.tag = new_tag.to_string();
+    for stream in leg.streams.iter() {
+      if let Some(key) = self.task_streams.get_mut(&stream.task_id) {
+        key.tag = new_tag.to_string();
+      }
+    }
+    call.legs.insert(new_tag.to_string(), leg);
+    true
+  }
+
+  pub fn has_branch(&self, call_id: u64, tag: &str) -> bool {
+    self.calls.get(&call_id).map_or(false, |call| {
+      call.legs.contains_key(tag)
+        || call
+          .legs
+          .values()
+          .any(|leg| leg.branches.iter().any(|(branch, _)| branch == tag))
+    })
+  }
+
+  pub fn remove_leg(&mut self, call_id: u64, tag: &str) -> Option<Leg> {
+    let call = self.calls.get_mut(&call_id)?;
+    let leg = call.legs.remove(tag)?;
+    Self::unindex_streams(&mut self.task_streams, &mut self.slot_tasks, &mut self.listening, &leg);
+    Some(leg)
   }
 
-  pub fn remove_call(&mut self, call_id: u64) {
-    if let Some(tasks) = self.calls.remove(&call_id) {
-      for task_id in tasks {
-        self.remove_task(&task_id);
+  pub fn remove_call(&mut self, call_id: u64) -> Option<Call> {
+    let call = self.calls.remove(&call_id)?;
+    for leg in call.legs.values() {
+      Self::unindex_streams(&mut self.task_streams, &mut self.slot_tasks, &mut self.listening, leg);
+    }
+    Some(call)
+  }
+
+  fn unindex_streams(
+    task_streams: &mut HashMap<TaskId, StreamKey>,
+    slot_tasks: &mut HashMap<usize, (TaskId, SocketKind)>,
+    listening: &mut HashMap<SocketAddr, (TaskId, SocketKind)>,
+    leg: &Leg,
+  ) {
+    for stream in leg.streams.iter() {
+      task_streams.remove(&stream.task_id);
+      for slot in stream.slot.iter().chain(stream.rtcp_slot.iter()) {
+        slot_tasks.remove(slot);
       }
+      listening.retain(|_, (task_id, _)| *task_id != stream.task_id);
     }
   }
 
-  pub fn add_backend(&mut self, addr: String, backend: usize) {
-    self.addr_backend_mapper.insert(addr, backend);
+  pub fn get_stream(&self, task_id: &TaskId) -> Option<&MediaStream> {
+    let key = self.task_streams.get(task_id)?;
+    self.calls.get(&key.call_id)?.legs.get(&key.tag)?.streams.get(key.index)
+  }
+
+  pub fn get_stream_mut(&mut self, task_id: &TaskId) -> Option<&mut MediaStream> {
+    let key = self.task_streams.get(task_id)?;
+    self
+      .calls
+      .get_mut(&key.call_id)?
+      .legs
+      .get_mut(&key.tag)?
+      .streams
+      .get_mut(key.index)
   }
 
-  pub fn get_backend(&self, addr: &str) -> Option<&usize> {
-    self.addr_backend_mapper.get(addr)
+  //a disabled stream gets port 0 and no socket, returns the slots of its sockets to close
+  pub fn close_stream(&mut self, task_id: &TaskId) -> Vec<usize> {
+    let stream = match self.get_stream_mut(task_id) {
+      Some(stream) => stream,
+      None => return vec![],
+    };
+    stream.port = 0;
+    let slots: Vec<usize> = stream.slot.take().into_iter().chain(stream.rtcp_slot.take()).collect();
+    for slot in slots.iter() {
+      self.slot_tasks.remove(slot);
+    }
+    self.listening.retain(|_, (id, _)| id != task_id);
+    slots
   }
 
-  pub fn get_backend_by_task(&self, task_id: &TaskId) -> Option<&usize> {
-    if let Some(addr) = self.task_addr_mapper.get(task_id) {
-      self.addr_backend_mapper.get(addr)
-    } else {
-      None
+  pub fn update_stream_stats(&mut self, task_id: &TaskId, ingress: TrafficCounters, egress: TrafficCounters) {
+    if let Some(stream) = self.get_stream_mut(task_id) {
+      stream.ingress = ingress;
+      stream.egress = egress;
     }
   }
 
-  pub fn get_task(&self, addr: &str) -> Option<&TaskId> {
-    self.addr_task_mapper.get(addr)
+  //returns the call of the stream
+  pub fn set_stream_idle(&mut self, task_id: &TaskId, idle: bool) -> Option<u64> {
+    let call_id = self.task_streams.get(task_id)?.call_id;
+    self.get_stream_mut(task_id)?.idle = idle;
+    Some(call_id)
+  }
+
+  pub fn is_call_idle(&self, call_id: u64) -> bool {
+    self.calls.get(&call_id).map_or(false, |call| {
+      call
+        .legs
+        .values()
+        .all(|leg| leg.streams.iter().all(|stream| stream.idle))
+    })
+  }
+
+  //calls with a final deadline at or before now
+  pub fn calls_past_deadline(&self, now: Instant) -> Vec<String> {
+    self
+      .calls
+      .values()
+      .filter(|call| call.final_deadline.is_some_and(|deadline| deadline <= now))
+      .map(|call| call.call_id.clone())
+      .collect()
+  }
+
+  pub fn add_listening(&mut self, bind: SocketAddr, task_id: TaskId, kind: SocketKind) {
+    self.listening.insert(bind, (task_id, kind));
+  }
+
+  //bind the socket slot returned by the backend to the task waiting on that address.
+  pub fn save_slot(&mut self, bind: &SocketAddr, slot: usize) -> Option<TaskId> {
+    let (task_id, kind) = self.listening.remove(bind)?;
+    let stream = self.get_stream_mut(&task_id)?;
+    match kind {
+      SocketKind::Rtp => stream.slot = Some(slot),
+      SocketKind::Rtcp => stream.rtcp_slot = Some(slot),
+    }
+    self.slot_tasks.insert(slot, (task_id, kind));
+    Some(task_id)
   }
 
-  pub fn get_slot_by_task(&self, task_id: &TaskId) -> Option<&usize> {
-    self.task_slot.get(task_id)
+  pub fn get_task_by_slot(&self, slot: usize) -> Option<(TaskId, SocketKind)> {
+    self.slot_tasks.get(&slot).copied()
   }
 
-  pub fn save_slot_task(&mut self, task_id: TaskId, slot: usize) {
-    self.task_slot.insert(task_id, slot);
+  pub fn get_slot_by_task(&self, task_id: &TaskId) -> Option<usize> {
+    self.get_stream(task_id)?.slot
   }
 
-  pub fn save_addr_task(&mut self, addr: String, task_id: TaskId) {
-    self.addr_leg_task.insert(addr, task_id);
+  pub fn get_rtcp_slot_by_task(&self, task_id: &TaskId) -> Option<usize> {
+    self.get_stream(task_id)?.rtcp_slot
   }
+}
+
+#[cfg(test)]
+mod test {
+  use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+  };
+
+  use crate::{runtime::worker::TaskId, PortRange, TrafficCounters};
+
+  use super::{CallMediaStore, Leg, MediaStream, SocketKind};
+
+  fn leg(tag: &str, task: usize, port: usize) -> Leg {
+    Leg {
+      tag: tag.to_string(),
+      branches: vec![],
+      created: 0,
+      remote_sdp: "".to_string(),
+      local_sdp: "".to_string(),
+      interface: 0,
+      rtcp_mux_flags: vec![],
+      streams: vec![MediaStream {
+        task_id: TaskId::Rtp(task),
+        port,
+        slot: None,
+        rtcp_slot: None,
+        rtcp_mux: false,
+        local_addr: format!("127.0.0.1:{}", port),
+        remote_addr: "127.0.0.1:4000".to_string(),
+        ingress: TrafficCounters::default(),
+        egress: TrafficCounters::default(),
+        idle: false,
+      }],
+    }
+  }
+
+  #[test]
+  fn find_leg_by_tag() {
+    let mut store = CallMediaStore::new(&[PortRange { min: 10000, max: 10010 }], Duration::from_secs(10));
+    store.add_leg(1, "call-1", leg("from", 0, 10000));
+    store.add_leg(1, "call-1", leg("to", 1, 10001));
+
+    assert_eq!(store.get_call(1).unwrap().call_id, "call-1");
+    assert_eq!(store.get_leg(1, "to").unwrap().streams[0].port, 10001);
+    assert!(store.get_leg(1, "other").is_none());
+    assert_eq!(store.list_calls(None), vec!["call-1".to_string()]);
+  }
+
+  #[test]
+  fn rename_pending_leg() {
+    let mut store = CallMediaStore::new(&[PortRange { min: 10000, max: 10010 }], Duration::from_secs(10));
+    store.add_leg(1, "call-1", leg("from", 0, 10000));
+    store.add_leg(1, "call-1", leg("", 1, 10001));
+
+    assert!(store.rename_leg(1, "", "to"));
+    assert!(store.get_leg(1, "").is_none());
+    assert_eq!(store.get_leg(1, "to").unwrap().tag, "to");
+    assert_eq!(store.get_stream(&TaskId::Rtp(1)).unwrap().port, 10001);
+    assert!(!store.rename_leg(1, "", "to"));
+  }
+
+  #[test]
+  fn forked_branches() {
+    let mut store = CallMediaStore::new(&[PortRange { min: 10000, max: 10010 }], Duration::from_secs(10));
+    store.add_leg(1, "call-1", leg("from", 0, 10000));
+    store.add_leg(1, "call-1", leg("", 1, 10001));
+    for (tag, new_tag) in [("", "branch-1"), ("branch-1", "branch-2"), ("branch-2", "branch-3")] {
+      store.rename_leg(1, tag, new_tag);
+      store.get_leg_mut(1, new_tag).unwrap().remote_sdp = format!("sdp of {}", new_tag);
+    }
+
+    let branches: Vec<&str> = store
+      .get_leg(1, "branch-3")
+      .unwrap()
+      .branches
+      .iter()
+      .map(|(tag, _)| tag.as_str())
+      .collect();
+    assert_eq!(branches, vec!["branch-1", "branch-2"]);
+    assert!(store.has_branch(1, "branch-1"));
+
+    assert_eq!(store.remove_branch(1, "branch-1"), Some("branch-3".to_string()));
+    assert!(!store.has_branch(1, "branch-1"));
+
+    // the leg goes back to the previous branch and what it answered
+    assert_eq!(store.remove_branch(1, "branch-3"), Some("branch-2".to_string()));
+    let leg = store.get_leg(1, "branch-2").unwrap();
+    assert_eq!(leg.streams[0].port, 10001);
+    assert_eq!(leg.remote_sdp, "sdp of branch-2");
+    assert_eq!(store.get_stream(&TaskId::Rtp(1)).map(|s| s.port), Some(10001));
+
+    assert_eq!(store.remove_branch(1, "branch-2"), None);
+    assert_eq!(store.remove_branch(1, "unknown"), None);
+  }
+
+  #[test]
+  fn released_port_quarantined() {
+    let mut store = CallMediaStore::new(&[PortRange { min: 10000, max: 10002 }], Duration::from_secs(10));
+    let now = Instant::now();
+    assert_eq!(store.next_port(0), Some(10000));
+    store.release_port(now, 0, 10000);
+    store.reclaim_ports(now + Duration::from_secs(5));
+    assert_eq!(store.next_port(0), None);
+    store.reclaim_ports(now + Duration::from_secs(10));
+    assert_eq!(store.next_port(0), Some(10000));
+  }
+
+  #[test]
+  fn stream_added_to_leg() {
+    let mut store = CallMediaStore::new(&[PortRange { min: 10000, max: 10010 }], Duration::from_secs(10));
+    store.add_leg(1, "call-1", leg("from", 0, 10000));
+    let video = leg("from", 1, 10002).streams.remove(0);
+
+    assert!(store.add_stream(1, "from", video));
+    assert_eq!(store.get_leg(1, "from").unwrap().streams.len(), 2);
+    assert_eq!(store.get_stream(&TaskId::Rtp(1)).map(|s| s.port), Some(10002));
+    let video = leg("other", 2, 10004).streams.remove(0);
+    assert!(!store.add_stream(1, "other", video));
+  }
+
+  #[test]
+  fn leg_stats_sum_streams() {
+    let mut from = leg("from", 0, 10000);
+    let mut video = leg("from", 1, 10002).streams.remove(0);
+    from.streams[0].ingress = TrafficCounters {
+      packets: 2,
+      bytes: 344,
+      errors: 0,
+    };
+    video.ingress = TrafficCounters {
+      packets: 3,
+      bytes: 3000,
+      errors: 1,
+    };
+    video.egress.packets = 1;
+    from.streams.push(video);
+
+    let stats = from.stats();
+    assert_eq!(stats.local_addr, "127.0.0.1:10000");
+    assert_eq!(
+      stats.ingress,
+      TrafficCounters {
+        packets: 5,
+        bytes: 3344,
+        errors: 1,
+      }
+    );
+    assert_eq!(stats.egress.packets, 1);
+  }
+
+  #[test]
+  fn even_port_pairs() {
+    let mut store = CallMediaStore::new(&[PortRange { min: 10001, max: 10008 }], Duration::from_secs(10));
+    assert_eq!(store.next_port(0), Some(10002));
+    assert_eq!(store.next_port(0), Some(10004));
+    assert_eq!(store.next_port(0), Some(10006));
+    assert_eq!(store.next_port(0), None);
+  }
+
+  #[test]
+  fn split_port_range() {
+    let range = PortRange { min: 10001, max: 10020 };
+    let ranges: Vec<PortRange> = (0..3).map(|index| range.split(3, index)).collect();
+    let mut store = CallMediaStore::new(&ranges, Duration::from_secs(10));
+    let mut ports = vec![];
+    for interface in 0..3 {
+      let mut count = 0;
+      while let Some(port) = store.next_port(interface) {
+        ports.push(port);
+        count += 1;
+      }
+      assert!(count > 0);
+    }
+    // every worker gets its own even ports, together they cover the whole range
+    assert_eq!(ports, (10002..10019).step_by(2).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn port_pool_per_interface() {
+    let ranges = [
+      PortRange { min: 10000, max: 10002 },
+      PortRange { min: 20000, max: 20002 },
+    ];
+    let mut store = CallMediaStore::new(&ranges, Duration::from_secs(10));
+    assert_eq!(store.next_port(1), Some(20000));
+    assert_eq!(store.next_port(1), None);
+    assert_eq!(store.next_port(0), Some(10000));
+    assert_eq!(store.next_port(2), None);
+  }
+
+  #[test]
+  fn call_idle_when_all_streams_idle() {
+    let mut store = CallMediaStore::new(&[PortRange { min: 10000, max: 10010 }], Duration::from_secs(10));
+    store.add_leg(1, "call-1", leg("from", 0, 10000));
+    store.add_leg(1, "call-1", leg("to", 1, 10001));
+
+    assert_eq!(store.set_stream_idle(&TaskId::Rtp(0), true), Some(1));
+    assert!(!store.is_call_idle(1));
+    store.set_stream_idle(&TaskId::Rtp(1), true);
+    assert!(store.is_call_idle(1));
+    store.set_stream_idle(&TaskId::Rtp(0), false);
+    assert!(!store.is_call_idle(1));
+  }
+
+  #[test]
+  fn slot_follows_stream() {
+    let mut store = CallMediaStore::new(&[PortRange { min: 10000, max: 10010 }], Duration::from_secs(10));
+    let bind = SocketAddr::from(([0, 0, 0, 0], 10000));
+    let rtcp_bind = SocketAddr::from(([0, 0, 0, 0], 10001));
+    store.add_leg(1, "call-1", leg("from", 0, 10000));
+    store.add_listening(bind, TaskId::Rtp(0), SocketKind::Rtp);
+    store.add_listening(rtcp_bind, TaskId::Rtp(0), SocketKind::Rtcp);
+
+    assert_eq!(store.save_slot(&bind, 3), Some(TaskId::Rtp(0)));
+    assert_eq!(store.save_slot(&rtcp_bind, 4), Some(TaskId::Rtp(0)));
+    assert_eq!(store.get_task_by_slot(3), Some((TaskId::Rtp(0), SocketKind::Rtp)));
+    assert_eq!(store.get_task_by_slot(4), Some((TaskId::Rtp(0), SocketKind::Rtcp)));
+    assert_eq!(store.get_slot_by_task(&TaskId::Rtp(0)), Some(3));
 
-  pub fn get_task_by_addr(&self, addr: &str) -> Option<&TaskId> {
-    self.addr_leg_task.get(addr)
+    let removed = store.remove_leg(1, "from").unwrap();
+    assert_eq!(removed.streams[0].slot, Some(3));
+    assert_eq!(removed.streams[0].rtcp_slot, Some(4));
+    assert!(store.get_task_by_slot(3).is_none());
+    assert!(store.get_task_by_slot(4).is_none());
+    assert!(store.get_stream(&TaskId::Rtp(0)).is_none());
   }
 }
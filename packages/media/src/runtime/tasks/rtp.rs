@@ -1,120 +1,225 @@
-use std::{net::SocketAddr, str::FromStr, time::Instant};
+use std::{
+  net::SocketAddr,
+  time::{Duration, Instant},
+};
 
 use sans_io_runtime::{collections::DynamicDeque, Buffer, BusChannelControl};
 
-use crate::{runtime::worker::ChannelId, util::get_sdp};
+use crate::{runtime::worker::ChannelId, TrafficCounters};
+
+const RTP_HEADER_LEN: usize = 12;
+const RTCP_HEADER_LEN: usize = 8;
+const STATS_REPORT_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone)]
 pub struct RtpForwardPacket {
   pub from: u64,
+  pub rtcp: bool,
   pub data: Buffer<'static>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpRemote {
+  pub rtp: SocketAddr,
+  pub rtcp: SocketAddr,
+}
+
 pub enum RtpInput<'a> {
   UdpPacket { data: Buffer<'a> },
-  Bus { from: u64, data: Buffer<'a> },
+  RtcpPacket { data: Buffer<'a> },
+  Bus { from: u64, rtcp: bool, data: Buffer<'a> },
+  Remote(Option<RtpRemote>),
+  //rtcp shares the rtp port with the remote
+  RtcpMux(bool),
+  IdleTimeout(Duration),
+  //the stream is released, the task leaves the call channel then asks to be destroyed
+  Shutdown,
 }
 
 #[derive(Debug)]
 pub enum RtpOutput {
+  //sent from the rtp socket
   Forward { to: SocketAddr, data: Buffer<'static> },
+  //sent from the rtcp socket
+  ForwardRtcp { to: SocketAddr, data: Buffer<'static> },
   Bus(BusChannelControl<ChannelId, RtpForwardPacket>),
   Destroy(usize),
+  //ingress, egress
+  Stats(TrafficCounters, TrafficCounters),
+  //no packet received within the idle timeout, or received again after that
+  Idle(bool),
 }
 
 pub struct RtpTask {
-  addr: SocketAddr,
-  call_id: u64,
+  addr: Option<RtpRemote>,
+  rtcp_mux: bool,
+  channel: ChannelId,
   leg_id: u64,
   rtp_port: usize,
+  //deadline for the next packet from the remote, None once the stream went idle or before it is armed
   timeout: Option<Instant>,
+  //None until the call is answered, a ringing call has no media to wait for
+  idle_timeout: Option<Duration>,
+  idle: bool,
+  last_activity: Instant,
+  ingress: TrafficCounters,
+  egress: TrafficCounters,
+  stats_changed: bool,
+  stats_report_at: Option<Instant>,
   output: DynamicDeque<RtpOutput, 16>,
 }
 
 impl RtpTask {
   pub fn build(
-    call_id: u64,
+    now: Instant,
+    channel: ChannelId,
     leg_id: u64,
     rtp_port: usize,
-    ip: &str,
-    sdp: &str,
-  ) -> Result<(Self, String, String), String> {
-    match get_sdp(sdp, ip, rtp_port) {
-      Err(e) => return Err(e),
-      Ok((addr, sdp)) => {
-        let mut output = DynamicDeque::default();
-        let sock_addr = SocketAddr::from_str(&addr).unwrap();
-        output.push_back_safe(RtpOutput::Bus(BusChannelControl::Subscribe(ChannelId::Call(call_id))));
-        let task = RtpTask {
-          addr: sock_addr,
-          call_id,
-          leg_id,
-          rtp_port,
-          timeout: None,
-          output,
-        };
-
-        Ok((task, addr.to_string(), sdp))
-      }
+    addr: Option<RtpRemote>,
+    idle_timeout: Option<Duration>,
+  ) -> Self {
+    let mut output = DynamicDeque::default();
+    output.push_back_safe(RtpOutput::Bus(BusChannelControl::Subscribe(channel)));
+    RtpTask {
+      addr,
+      rtcp_mux: false,
+      channel,
+      leg_id,
+      rtp_port,
+      timeout: idle_timeout.map(|idle_timeout| now + idle_timeout),
+      idle_timeout,
+      idle: false,
+      last_activity: now,
+      ingress: TrafficCounters::default(),
+      egress: TrafficCounters::default(),
+      stats_changed: false,
+      stats_report_at: None,
+      output,
     }
   }
 
-  pub fn pop_event_inner(&mut self, now: Instant, has_input: bool) -> Option<RtpOutput> {
-    if let Some(o) = self.output.pop_front() {
-      return Some(o);
-    }
+  pub fn pop_event_inner(&mut self, _now: Instant, _has_input: bool) -> Option<RtpOutput> {
+    self.output.pop_front()
+  }
 
-    if !has_input {
-      if let Some(timeout) = self.timeout {
-        if timeout > now {
-          return None;
-        }
-      }
-    }
+  // rtcp packet types 192..=223 never collide with rtp payload types (RFC 5761)
+  fn is_rtcp(data: &[u8]) -> bool {
+    data.len() >= 2 && (192..=223).contains(&data[1])
+  }
 
-    None
+  fn on_remote_packet(&mut self, now: Instant, data: &[u8], rtcp: bool) {
+    self.stats_changed = true;
+    let min_len = if rtcp {
+      RTCP_HEADER_LEN
+    } else {
+      RTP_HEADER_LEN
+    };
+    if data.len() < min_len {
+      self.ingress.errors += 1;
+      return;
+    }
+    self.ingress.packets += 1;
+    self.ingress.bytes += data.len() as u64;
+    let buffer = Buffer::from(data.to_vec());
+    self.output.push_back_safe(RtpOutput::Bus(BusChannelControl::Publish(
+      self.channel,
+      true,
+      RtpForwardPacket {
+        from: self.leg_id,
+        rtcp,
+        data: buffer.into(),
+      },
+    )));
+    if self.idle {
+      self.idle = false;
+      self.output.push_back_safe(RtpOutput::Idle(false));
+    }
+    self.last_activity = now;
+    self.timeout = self.idle_timeout.map(|idle_timeout| now + idle_timeout);
   }
 }
 
 impl RtpTask {
   pub fn on_tick<'a>(&mut self, now: Instant) -> Option<RtpOutput> {
+    // counters are reported to the worker at most once per interval, so a query can be up to one interval behind
+    if self.stats_changed && self.stats_report_at.map_or(true, |at| at <= now) {
+      self.stats_changed = false;
+      self.stats_report_at = Some(now + STATS_REPORT_INTERVAL);
+      self.output.push_back_safe(RtpOutput::Stats(self.ingress, self.egress));
+    }
+
     if let Some(timeout) = self.timeout {
-      if now < timeout {
-        return None;
+      if timeout <= now {
+        self.timeout = None;
+        self.idle = true;
+        self.output.push_back_safe(RtpOutput::Idle(true));
       }
     }
 
-    self.timeout = None;
     self.pop_event_inner(now, true)
   }
 
   pub fn on_event<'a>(&mut self, now: Instant, input: RtpInput<'a>) -> Option<RtpOutput> {
     match input {
       RtpInput::UdpPacket { data } => {
-        let buffer = Buffer::from(data.to_vec());
-        self.output.push_back_safe(RtpOutput::Bus(BusChannelControl::Publish(
-          ChannelId::Call(self.call_id),
-          true,
-          RtpForwardPacket {
-            from: self.leg_id,
-            data: buffer.into(),
-          },
-        )));
-        self.timeout = None;
+        // the remote may send rtcp on the rtp port when muxing
+        let rtcp = Self::is_rtcp(&data);
+        self.on_remote_packet(now, &data, rtcp);
         self.pop_event_inner(now, true)
       }
-      RtpInput::Bus { from, data } => {
+      RtpInput::RtcpPacket { data } => {
+        self.on_remote_packet(now, &data, true);
+        self.pop_event_inner(now, true)
+      }
+      RtpInput::Bus { from, rtcp, data } => {
         if from != self.leg_id {
-          let buffer = Buffer::from(data.to_vec());
-          self.output.push_back_safe(RtpOutput::Forward {
-            to: self.addr,
-            data: buffer.into(),
-          });
+          self.stats_changed = true;
+          match self.addr {
+            Some(remote) => {
+              self.egress.packets += 1;
+              self.egress.bytes += data.len() as u64;
+              let buffer = Buffer::from(data.to_vec());
+              let out = if rtcp && !self.rtcp_mux {
+                RtpOutput::ForwardRtcp {
+                  to: remote.rtcp,
+                  data: buffer.into(),
+                }
+              } else {
+                RtpOutput::Forward {
+                  to: remote.rtp,
+                  data: buffer.into(),
+                }
+              };
+              self.output.push_back_safe(out);
+            }
+            // the remote side has not answered yet or is on hold
+            None => self.egress.errors += 1,
+          }
         }
 
-        self.timeout = None;
         self.pop_event_inner(now, true)
       }
+      RtpInput::Remote(addr) => {
+        self.addr = addr;
+        self.pop_event_inner(now, true)
+      }
+      RtpInput::RtcpMux(rtcp_mux) => {
+        self.rtcp_mux = rtcp_mux;
+        self.pop_event_inner(now, true)
+      }
+      RtpInput::IdleTimeout(idle_timeout) => {
+        // the first timeout arms the stream from now, a later one moves the running deadline
+        let since = match self.idle_timeout {
+          Some(_) => self.last_activity,
+          None => now,
+        };
+        self.idle_timeout = Some(idle_timeout);
+        if !self.idle {
+          self.timeout = Some(since + idle_timeout);
+        }
+        self.pop_event_inner(now, true)
+      }
+      RtpInput::Shutdown => self.shutdown(now),
     }
   }
 
@@ -123,7 +228,233 @@ impl RtpTask {
   }
 
   pub fn shutdown(&mut self, now: Instant) -> Option<RtpOutput> {
+    self
+      .output
+      .push_back_safe(RtpOutput::Bus(BusChannelControl::Unsubscribe(self.channel)));
     self.output.push_back_safe(RtpOutput::Destroy(self.rtp_port));
     self.pop_event_inner(now, true)
   }
 }
+
+#[cfg(test)]
+mod test {
+  use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+  };
+
+  use sans_io_runtime::{Buffer, BusChannelControl};
+
+  use crate::{runtime::worker::ChannelId, TrafficCounters};
+
+  use super::{RtpInput, RtpOutput, RtpRemote, RtpTask};
+
+  const LEG: u64 = 1;
+  const PEER_LEG: u64 = 2;
+
+  fn remote() -> RtpRemote {
+    RtpRemote {
+      rtp: "192.168.1.10:4000".parse().unwrap(),
+      rtcp: "192.168.1.10:4001".parse().unwrap(),
+    }
+  }
+
+  // the task with its subscription to the call channel already popped
+  fn task(now: Instant, remote: Option<RtpRemote>) -> RtpTask {
+    let mut task = RtpTask::build(
+      now,
+      ChannelId::Call(1, 0),
+      LEG,
+      10000,
+      remote,
+      Some(Duration::from_secs(60)),
+    );
+    assert!(matches!(
+      task.pop_output(now),
+      Some(RtpOutput::Bus(BusChannelControl::Subscribe(_)))
+    ));
+    task
+  }
+
+  fn outputs(task: &mut RtpTask, now: Instant, first: Option<RtpOutput>) -> Vec<RtpOutput> {
+    let mut outputs: Vec<RtpOutput> = first.into_iter().collect();
+    while let Some(out) = task.pop_output(now) {
+      outputs.push(out);
+    }
+    outputs
+  }
+
+  fn packet(len: usize) -> Buffer<'static> {
+    let mut data = vec![0; len];
+    data[0] = 0x80;
+    Buffer::from(data)
+  }
+
+  fn stats(task: &mut RtpTask, now: Instant) -> Option<(TrafficCounters, TrafficCounters)> {
+    let first = task.on_tick(now);
+    outputs(task, now, first).into_iter().find_map(|out| match out {
+      RtpOutput::Stats(ingress, egress) => Some((ingress, egress)),
+      _ => None,
+    })
+  }
+
+  fn forwarded_to(outputs: &[RtpOutput]) -> Vec<(bool, SocketAddr)> {
+    outputs
+      .iter()
+      .filter_map(|out| match out {
+        RtpOutput::Forward { to, .. } => Some((false, *to)),
+        RtpOutput::ForwardRtcp { to, .. } => Some((true, *to)),
+        _ => None,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn count_traffic() {
+    let now = Instant::now();
+    let mut task = task(now, Some(remote()));
+
+    let first = task.on_event(now, RtpInput::UdpPacket { data: packet(172) });
+    let published = outputs(&mut task, now, first);
+    assert!(matches!(
+      published.as_slice(),
+      [RtpOutput::Bus(BusChannelControl::Publish(ChannelId::Call(1, 0), true, packet))] if packet.from == LEG && !packet.rtcp
+    ));
+    let first = task.on_event(
+      now,
+      RtpInput::Bus {
+        from: PEER_LEG,
+        rtcp: false,
+        data: packet(100),
+      },
+    );
+    assert_eq!(
+      forwarded_to(&outputs(&mut task, now, first)),
+      vec![(false, remote().rtp)]
+    );
+    // the task hears its own publications on the channel, they are not sent back
+    let first = task.on_event(
+      now,
+      RtpInput::Bus {
+        from: LEG,
+        rtcp: false,
+        data: packet(172),
+      },
+    );
+    assert!(outputs(&mut task, now, first).is_empty());
+
+    let expected = (
+      TrafficCounters {
+        packets: 1,
+        bytes: 172,
+        errors: 0,
+      },
+      TrafficCounters {
+        packets: 1,
+        bytes: 100,
+        errors: 0,
+      },
+    );
+    assert_eq!(stats(&mut task, now), Some(expected));
+  }
+
+  #[test]
+  fn stats_reported_each_interval() {
+    let now = Instant::now();
+    let mut task = task(now, Some(remote()));
+    assert_eq!(stats(&mut task, now), None);
+
+    task.on_event(now, RtpInput::UdpPacket { data: packet(172) });
+    assert_eq!(stats(&mut task, now).map(|(ingress, _)| ingress.packets), Some(1));
+    task.on_event(now, RtpInput::UdpPacket { data: packet(172) });
+    assert_eq!(stats(&mut task, now + Duration::from_millis(999)), None);
+    let later = now + Duration::from_secs(1);
+    assert_eq!(stats(&mut task, later).map(|(ingress, _)| ingress.packets), Some(2));
+    // nothing new, nothing reported
+    assert_eq!(stats(&mut task, later + Duration::from_secs(1)), None);
+  }
+
+  #[test]
+  fn short_packet_is_an_error() {
+    let now = Instant::now();
+    let mut task = task(now, Some(remote()));
+
+    let first = task.on_event(now, RtpInput::UdpPacket { data: packet(4) });
+    assert!(outputs(&mut task, now, first).is_empty());
+    let (ingress, _) = stats(&mut task, now).expect("stats");
+    assert_eq!(
+      ingress,
+      TrafficCounters {
+        packets: 0,
+        bytes: 0,
+        errors: 1,
+      }
+    );
+  }
+
+  #[test]
+  fn rtcp_on_rtp_port() {
+    let now = Instant::now();
+    let mut task = task(now, Some(remote()));
+
+    // rtcp receiver report, packet type 201
+    let mut rtcp = vec![0; 32];
+    rtcp[0] = 0x81;
+    rtcp[1] = 201;
+    for (data, expected) in [(Buffer::from(rtcp), true), (packet(172), false)] {
+      let first = task.on_event(now, RtpInput::UdpPacket { data });
+      let published = outputs(&mut task, now, first);
+      assert!(matches!(
+        published.as_slice(),
+        [RtpOutput::Bus(BusChannelControl::Publish(_, _, packet))] if packet.rtcp == expected
+      ));
+    }
+  }
+
+  #[test]
+  fn rtcp_forwarded_following_mux() {
+    let now = Instant::now();
+    let mut task = task(now, Some(remote()));
+    let forward = |task: &mut RtpTask, rtcp: bool| {
+      let first = task.on_event(
+        now,
+        RtpInput::Bus {
+          from: PEER_LEG,
+          rtcp,
+          data: packet(32),
+        },
+      );
+      forwarded_to(&outputs(task, now, first))
+    };
+
+    assert_eq!(forward(&mut task, false), vec![(false, remote().rtp)]);
+    assert_eq!(forward(&mut task, true), vec![(true, remote().rtcp)]);
+    task.on_event(now, RtpInput::RtcpMux(true));
+    assert_eq!(forward(&mut task, true), vec![(false, remote().rtp)]);
+  }
+
+  #[test]
+  fn no_remote_is_an_egress_error() {
+    let now = Instant::now();
+    let mut task = task(now, None);
+
+    let first = task.on_event(
+      now,
+      RtpInput::Bus {
+        from: PEER_LEG,
+        rtcp: false,
+        data: packet(100),
+      },
+    );
+    assert!(outputs(&mut task, now, first).is_empty());
+    let (_, egress) = stats(&mut task, now).expect("stats");
+    assert_eq!(
+      egress,
+      TrafficCounters {
+        packets: 0,
+        bytes: 0,
+        errors: 1,
+      }
+    );
+  }
+}
@@ -1,8 +1,9 @@
 use std::{
   collections::VecDeque,
   hash::{DefaultHasher, Hash, Hasher},
-  net::SocketAddr,
-  time::Instant,
+  net::{IpAddr, SocketAddr},
+  str::FromStr,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use derive_more::Display;
@@ -12,14 +13,29 @@ use sans_io_runtime::{
   group_owner_type, group_task, Buffer, BusControl, BusEvent, TaskSwitcher, WorkerInner, WorkerInnerInput,
   WorkerInnerOutput,
 };
+use sdp::{description::session::Origin, SessionDescription};
 
-use crate::{MediaRpcCmd, MediaRpcRequest, MediaRpcResponse};
+use crate::{
+  util::{
+    address_type, generate_sdp, get_origin, get_sdp, is_hold_sdp, join_addr, same_session, MediaConfig, RemoteSdp,
+    SdpConfig, SDP_ANONYMOUS,
+  },
+  AddressFamily, EndRequest, MediaRpcCmd, MediaRpcRequest, MediaRpcResponse, Replace, RtcpMux, SdpExchange, SdpOptions,
+  TrafficCounters,
+};
 
 use super::{
-  store::CallMediaStore,
-  tasks::{RtpForwardPacket, RtpInput, RtpOutput, RtpTask},
+  store::{CallMediaStore, Leg, MediaStream, SocketKind},
+  tasks::{RtpForwardPacket, RtpInput, RtpOutput, RtpRemote, RtpTask},
 };
 
+fn unix_timestamp() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or_default()
+}
+
 #[repr(u8)]
 pub enum TaskType {
   Rtp = 0,
@@ -39,7 +55,9 @@ group_task!(RtpTaskGroup, RtpTask, RtpInput<'a>, RtpOutput);
 
 #[derive(Display, Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum ChannelId {
-  Call(u64),
+  //call_id, m-line index
+  #[display(fmt = "Call({}, {})", _0, _1)]
+  Call(u64, usize),
 }
 
 #[derive(convert_enum::From, Debug, Clone, Copy, PartialEq)]
@@ -56,7 +74,9 @@ pub enum TaskId {
 
 #[derive(Debug, Clone)]
 pub enum ExtInput {
-  Rpc(MediaRpcRequest),
+  Rpc(Box<MediaRpcRequest>),
+  //stop accepting new calls, the running ones keep going until they end
+  Drain,
 }
 
 #[derive(convert_enum::From, Debug, Clone)]
@@ -64,9 +84,21 @@ pub enum RtpEvent {
   Foward(RtpForwardPacket),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallEndReason {
+  Timeout,
+  SilentTimeout,
+  FinalTimeout,
+  Shutdown,
+}
+
 #[derive(Debug, Clone)]
 pub enum ExtOut {
   Rpc(MediaRpcResponse),
+  //call_id, reason
+  CallEnded(String, CallEndReason),
+  //worker, sent once a draining worker has no call left
+  Drained(u16),
 }
 
 pub enum SCfg {
@@ -74,24 +106,68 @@ pub enum SCfg {
   Invite(String, String, String),
 }
 
+#[derive(Debug, Clone)]
 pub struct PortRange {
   pub min: usize,
   pub max: usize,
 }
 
-pub struct Config {
-  pub ip: String,
+impl PortRange {
+  //the index-th of `parts` disjoint sub-ranges, each one starting on an even port
+  pub fn split(&self, parts: usize, index: usize) -> PortRange {
+    let min = self.min + self.min % 2;
+    let chunk = (self.max.saturating_sub(min) / parts.max(1)) & !1;
+    PortRange {
+      min: min + index * chunk,
+      max: if index + 1 >= parts {
+        self.max
+      } else {
+        min + (index + 1) * chunk
+      },
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Interface {
+  pub name: String,
+  //local address the media sockets are bound to
+  pub bind: IpAddr,
+  //address put in the sdp for the endpoints
+  pub advertised: String,
   pub port_range: PortRange,
 }
 
+pub struct Config {
+  //the first interface of an address family is the default one for that family
+  pub interfaces: Vec<Interface>,
+  //a call without any received media for this long is ended
+  pub timeout: Duration,
+  //same as timeout, for calls with media on hold
+  pub silent_timeout: Duration,
+  //calls are ended after this long whatever their activity
+  pub final_timeout: Option<Duration>,
+  //released ports are kept out of the pool this long, so late packets don't reach a new call
+  pub port_quarantine: Duration,
+}
+
 pub struct RtpEngineMediaWorker {
   worker: u16,
-  ip: String,
+  interfaces: Vec<Interface>,
   rtp_group: RtpTaskGroup,
   output: VecDeque<WorkerInnerOutput<'static, OwnerType, ExtOut, ChannelId, RtpEvent, SCfg>>,
   store: CallMediaStore,
   switcher: TaskSwitcher,
   shutdown: bool,
+  //None when not draining, else whether the end of the drain was reported
+  drained: Option<bool>,
+  stream_seq: u64,
+  delayed_deletes: Vec<(Instant, EndRequest)>,
+  timeout: Duration,
+  silent_timeout: Duration,
+  final_timeout: Option<Duration>,
+  idle_calls: Vec<u64>,
+  final_timeout_check_at: Option<Instant>,
 }
 
 impl RtpEngineMediaWorker {
@@ -101,72 +177,629 @@ impl RtpEngineMediaWorker {
     hasher.finish()
   }
 
-  pub fn new_leg(&mut self, call_id: String, leg_id: String, sdp: String) -> Result<String, String> {
-    let call_id_hashed = Self::channel_build(&call_id);
-    let leg_id_hashed = Self::channel_build(&leg_id);
-    let rtp_port = self.store.next_port();
-    if rtp_port.is_none() {
-      return Err("No available port".to_string());
+  //the worker owning a call, all the commands of the call must be sent to it
+  pub fn worker_for_call(call_id: &str, workers: usize) -> u16 {
+    (Self::channel_build(call_id) % workers.max(1) as u64) as u16
+  }
+
+  fn allocate_ports(&mut self, interface: usize, count: usize) -> Result<Vec<usize>, String> {
+    let mut ports = Vec::with_capacity(count);
+    while ports.len() < count {
+      match self.store.next_port(interface) {
+        Some(port) => ports.push(port),
+        None => {
+          for port in ports {
+            self.store.push_port(interface, port);
+          }
+          return Err("No available port".to_string());
+        }
+      }
     }
-    let port = rtp_port.unwrap();
-    let res = RtpTask::build(call_id_hashed, leg_id_hashed, rtp_port.unwrap(), &self.ip, &sdp);
-    match res {
-      Ok((task, _addr, sdp)) => {
-        let idx = self.rtp_group.add_task(task);
-        let bind_addr = SocketAddr::from(([0, 0, 0, 0], port as u16));
-        self.store.add_task(_addr, TaskId::Rtp(idx));
-        self.store.add_call(call_id_hashed, TaskId::Rtp(idx));
-        self.store.save_addr_task(bind_addr.to_string(), TaskId::Rtp(idx));
-        self.output.push_back(WorkerInnerOutput::Net(
-          OwnerType::System,
-          BackendOutgoing::UdpListen {
-            addr: bind_addr,
-            reuse: false,
-          },
-        ));
-        Ok(sdp)
+    Ok(ports)
+  }
+
+  // one remote address per m-line; a connection address of 0.0.0.0 or a zero port puts that stream
+  // on hold, nothing is sent to it
+  fn parse_remote_sdp(&self, sdp: &str) -> Result<(Vec<Option<RtpRemote>>, RemoteSdp), String> {
+    let remote = get_sdp(sdp).map_err(|e| e.to_string())?;
+    let mut addrs = Vec::with_capacity(remote.media.len());
+    for media in remote.media.iter() {
+      let rtp = SocketAddr::from_str(&media.rtp_addr).map_err(|e| format!("invalid media address: {}", e))?;
+      let rtcp = SocketAddr::from_str(&media.rtcp_addr).map_err(|e| format!("invalid rtcp address: {}", e))?;
+      if rtp.ip().is_unspecified() || rtp.port() == 0 {
+        addrs.push(None);
+      } else {
+        addrs.push(Some(RtpRemote { rtp, rtcp }));
+      }
+    }
+    Ok((addrs, remote))
+  }
+
+  fn interface_by_name(&self, name: &str) -> Result<usize, String> {
+    self
+      .interfaces
+      .iter()
+      .position(|interface| interface.name == name)
+      .ok_or(format!("Unknown interface {}", name))
+  }
+
+  fn interface_by_family(&self, family: AddressFamily) -> Result<usize, String> {
+    self
+      .interfaces
+      .iter()
+      .position(|interface| interface.bind.is_ipv6() == (family == AddressFamily::Ipv6))
+      .ok_or(format!("No interface for {:?}", family))
+  }
+
+  // the family of the first media address of the remote, ipv4 when everything is on hold
+  fn remote_family(remote_addrs: &[Option<RtpRemote>]) -> AddressFamily {
+    match remote_addrs.iter().flatten().next() {
+      Some(remote) if remote.rtp.is_ipv6() => AddressFamily::Ipv6,
+      _ => AddressFamily::Ipv4,
+    }
+  }
+
+  // `previous` is the last description sent to the same endpoint. With the origin or sdp-version
+  // replace flags the engine owns the version: it is kept from the previous description and only
+  // increases when something else changed. The origin flag also keeps the first username and session
+  // id sent to the endpoint, so forks and re-offers from the other side don't show through.
+  // The stripped and masked codecs are not offered on: the engine relays without transcoding, so
+  // both only remove them from the description.
+  fn local_sdp(
+    &self,
+    remote: &SessionDescription,
+    interface: usize,
+    ports: &[usize],
+    rtcp_mux: &[bool],
+    previous: &str,
+    options: &SdpOptions,
+  ) -> String {
+    let replace = &options.replace;
+    let addr = &self.interfaces[interface].advertised;
+    let previous_origin = get_origin(previous);
+    let mut origin = remote.origin.clone();
+    if replace.contains(&Replace::Origin) {
+      origin.network_type = "IN".to_string();
+      origin.address_type = address_type(addr).to_string();
+      origin.unicast_address = addr.clone();
+      if let Some(previous) = &previous_origin {
+        origin.username = previous.username.clone();
+        origin.session_id = previous.session_id;
+      }
+    }
+    if replace.contains(&Replace::Username) {
+      origin.username = SDP_ANONYMOUS.to_string();
+    }
+    let engine_version = replace.contains(&Replace::Origin) || replace.contains(&Replace::SdpVersion);
+    if let (true, Some(previous)) = (engine_version, &previous_origin) {
+      origin.session_version = previous.session_version;
+    }
+    let generate = |origin: Origin| {
+      generate_sdp(
+        remote,
+        SdpConfig {
+          addr: addr.clone(),
+          media: ports
+            .iter()
+            .zip(rtcp_mux)
+            .map(|(port, rtcp_mux)| MediaConfig {
+              rtp_port: *port as isize,
+              rtcp_port: *port as isize + 1,
+              rtcp_mux: *rtcp_mux,
+            })
+            .collect(),
+          origin: Some(origin),
+          session_name: replace
+            .contains(&Replace::SessionName)
+            .then(|| SDP_ANONYMOUS.to_string()),
+          remove_codecs: options
+            .codec
+            .strip
+            .iter()
+            .chain(options.codec.mask.iter())
+            .cloned()
+            .collect(),
+        },
+      )
+    };
+    let sdp = generate(origin.clone());
+    if engine_version && previous_origin.is_some() && !same_session(previous, &sdp) {
+      origin.session_version += 1;
+      return generate(origin);
+    }
+    sdp
+  }
+
+  // whether the offer sent on to the callee carries rtcp-mux
+  fn offer_rtcp_mux(flags: &[RtcpMux], offered: bool) -> bool {
+    flags.contains(&RtcpMux::Offer) || (offered && !flags.contains(&RtcpMux::Demux))
+  }
+
+  // whether the answer sent back to the caller accepts its rtcp-mux
+  fn answer_rtcp_mux(flags: &[RtcpMux], offered: bool, answered: bool) -> bool {
+    offered
+      && !flags.contains(&RtcpMux::Reject)
+      && (answered || flags.contains(&RtcpMux::Accept) || flags.contains(&RtcpMux::Demux))
+  }
+
+  fn sdp_rtcp_mux(sdp: &str) -> Vec<bool> {
+    get_sdp(sdp)
+      .map(|remote| remote.media.iter().map(|media| media.rtcp_mux).collect())
+      .unwrap_or_default()
+  }
+
+  // streams of the same m-line share a sub-channel of the call, so media types never mix
+  fn new_stream(
+    &mut self,
+    now: Instant,
+    call_id: u64,
+    index: usize,
+    interface: usize,
+    port: usize,
+    remote: Option<RtpRemote>,
+  ) -> MediaStream {
+    self.stream_seq += 1;
+    let channel = ChannelId::Call(call_id, index);
+    let idle_timeout = self.store.get_call(call_id).and_then(|call| call.idle_timeout);
+    let task = RtpTask::build(now, channel, self.stream_seq, port, remote, idle_timeout);
+    let task_id = TaskId::Rtp(self.rtp_group.add_task(task));
+    if port != 0 {
+      self.listen_stream(interface, task_id, port);
+    }
+    MediaStream {
+      task_id,
+      port,
+      slot: None,
+      rtcp_slot: None,
+      rtcp_mux: false,
+      local_addr: join_addr(&self.interfaces[interface].advertised, port),
+      remote_addr: remote.map(|addr| addr.rtp.to_string()).unwrap_or_default(),
+      ingress: TrafficCounters::default(),
+      egress: TrafficCounters::default(),
+      idle: false,
+    }
+  }
+
+  fn listen_stream(&mut self, interface: usize, task_id: TaskId, port: usize) {
+    let bind_ip = self.interfaces[interface].bind;
+    for (port, kind) in [(port, SocketKind::Rtp), (port + 1, SocketKind::Rtcp)] {
+      let bind_addr = SocketAddr::new(bind_ip, port as u16);
+      self.store.add_listening(bind_addr, task_id, kind);
+      self.output.push_back(WorkerInnerOutput::Net(
+        OwnerType::System,
+        BackendOutgoing::UdpListen {
+          addr: bind_addr,
+          reuse: false,
+        },
+      ));
+    }
+  }
+
+  // a re-offer can add m-lines, both legs get a stream for each new one, its ports come with
+  // update_leg_ports
+  fn add_missing_streams(&mut self, now: Instant, call_id: u64, tags: [&str; 2], count: usize) {
+    for tag in tags {
+      let (existing, interface) = match self.store.get_leg(call_id, tag) {
+        Some(leg) => (leg.streams.len(), leg.interface),
+        None => continue,
+      };
+      for index in existing..count {
+        let stream = self.new_stream(now, call_id, index, interface, 0, None);
+        self.store.add_stream(call_id, tag, stream);
+      }
+    }
+  }
+
+  // whether each m-line is enabled, a port 0 rejects or disables it (RFC 3264 6, 8.2)
+  fn enabled_media(remote: &RemoteSdp) -> Vec<bool> {
+    remote
+      .sdp
+      .media_descriptions
+      .iter()
+      .map(|media| media.media_name.port.value != 0)
+      .collect()
+  }
+
+  // The streams of disabled m-lines have port 0: no socket and nothing taken from the pool. They
+  // close and give their port back when a description disables them, and get a port again when a
+  // later one enables them. M-lines missing from `enabled` are left as they are.
+  fn update_leg_ports(&mut self, now: Instant, call_id: u64, tag: &str, enabled: &[bool]) -> Result<(), String> {
+    let (interface, streams): (usize, Vec<(TaskId, usize)>) = match self.store.get_leg(call_id, tag) {
+      Some(leg) => (
+        leg.interface,
+        leg.streams.iter().map(|stream| (stream.task_id, stream.port)).collect(),
+      ),
+      None => return Ok(()),
+    };
+    for ((task_id, port), enabled) in streams.into_iter().zip(enabled) {
+      if *enabled && port == 0 {
+        let port = self.allocate_ports(interface, 1)?[0];
+        self.listen_stream(interface, task_id, port);
+        let local_addr = join_addr(&self.interfaces[interface].advertised, port);
+        if let Some(stream) = self.store.get_stream_mut(&task_id) {
+          stream.port = port;
+          stream.local_addr = local_addr;
+        }
+      } else if !*enabled && port != 0 {
+        for slot in self.store.close_stream(&task_id) {
+          self.output.push_back(WorkerInnerOutput::Net(
+            OwnerType::System,
+            BackendOutgoing::UdpUnlisten { slot },
+          ));
+        }
+        self.store.release_port(now, interface, port);
+      }
+    }
+    Ok(())
+  }
+
+  // the stream ports of the leg in m-line order, which is what its endpoint sends media to
+  fn leg_ports(&self, call_id: u64, tag: &str) -> Vec<usize> {
+    self
+      .store
+      .get_leg(call_id, tag)
+      .map(|leg| leg.streams.iter().map(|stream| stream.port).collect())
+      .unwrap_or_default()
+  }
+
+  // apply a new remote description to a leg, keeping its ports and tasks
+  fn update_leg_remote(&mut self, now: Instant, call_id: u64, tag: &str, sdp: String, remotes: Vec<Option<RtpRemote>>) {
+    let mut tasks = vec![];
+    if let Some(leg) = self.store.get_leg_mut(call_id, tag) {
+      leg.remote_sdp = sdp;
+      for (index, stream) in leg.streams.iter_mut().enumerate() {
+        // m-lines missing from the description are on hold
+        let remote = remotes.get(index).copied().flatten();
+        stream.remote_addr = remote.map(|addr| addr.rtp.to_string()).unwrap_or_default();
+        tasks.push((stream.task_id, remote));
+      }
+    }
+    for (task_id, remote) in tasks {
+      self.send_to_task(now, task_id, RtpInput::Remote(remote));
+    }
+  }
+
+  fn set_leg_rtcp_mux(&mut self, now: Instant, call_id: u64, tag: &str, rtcp_mux: &[bool]) {
+    let mut tasks = vec![];
+    if let Some(leg) = self.store.get_leg_mut(call_id, tag) {
+      for (index, stream) in leg.streams.iter_mut().enumerate() {
+        stream.rtcp_mux = rtcp_mux.get(index).copied().unwrap_or(false);
+        tasks.push((stream.task_id, stream.rtcp_mux));
+      }
+    }
+    for (task_id, rtcp_mux) in tasks {
+      self.send_to_task(now, task_id, RtpInput::RtcpMux(rtcp_mux));
+    }
+  }
+
+  fn send_to_task(&mut self, now: Instant, task_id: TaskId, input: RtpInput<'static>) {
+    match task_id {
+      TaskId::Rtp(index) => {
+        if let Some(out) = self.rtp_group.on_event(now, index, input) {
+          if let Some(out) = self.process_rtp_out(index, out) {
+            self.output.push_back(out);
+          }
+        }
+      }
+    }
+  }
+
+  fn set_leg_local_sdp(&mut self, call_id: u64, tag: &str, sdp: &str) {
+    if let Some(leg) = self.store.get_leg_mut(call_id, tag) {
+      leg.local_sdp = sdp.to_string();
+    }
+  }
+
+  // The offer creates both sides of the call: the leg of the offerer, and a leg without tag for the
+  // side the offer is sent to, each with a stream per m-line. The returned sdp advertises the ports
+  // of that second leg. An offer from a known tag is a re-offer and only updates the offerer leg, one
+  // from another tag is refused so a misrouted offer can't end the media of a running call.
+  pub fn process_offer(&mut self, now: Instant, req: SdpExchange) -> Result<String, String> {
+    let call_id_hashed = Self::channel_build(&req.call_id);
+    if self.drained.is_some() && self.store.get_call(call_id_hashed).is_none() {
+      return Err("Shutting down, no new call is accepted".to_string());
+    }
+    self.cancel_delayed_delete(&req.call_id);
+    if let Some(call) = self.store.get_call(call_id_hashed) {
+      if call.legs.contains_key(&req.from_tag) {
+        let peer_tag = req
+          .to_tag
+          .as_ref()
+          .filter(|tag| call.legs.contains_key(*tag))
+          .or_else(|| call.legs.keys().find(|tag| **tag != req.from_tag))
+          .cloned()
+          .ok_or("Unknown to-tag".to_string())?;
+        let (remote_addrs, remote_sdp) = self.parse_remote_sdp(&req.sdp)?;
+        self.add_missing_streams(now, call_id_hashed, [&req.from_tag, &peer_tag], remote_addrs.len());
+        let enabled = Self::enabled_media(&remote_sdp);
+        for tag in [&req.from_tag, &peer_tag] {
+          self.update_leg_ports(now, call_id_hashed, tag, &enabled)?;
+        }
+        let peer_ports = self.leg_ports(call_id_hashed, &peer_tag);
+        let (peer_interface, peer_local_sdp) = self
+          .store
+          .get_leg(call_id_hashed, &peer_tag)
+          .map_or((0, String::new()), |leg| (leg.interface, leg.local_sdp.clone()));
+        let rtcp_mux: Vec<bool> = remote_sdp
+          .media
+          .iter()
+          .map(|media| Self::offer_rtcp_mux(&req.rtcp_mux, media.rtcp_mux))
+          .collect();
+        let local_sdp = self.local_sdp(
+          &remote_sdp.sdp, peer_interface, &peer_ports, &rtcp_mux, &peer_local_sdp, &req.options,
+        );
+        debug!("re-offer of call {} from {}", req.call_id, req.from_tag);
+        // the offerer keeps its muxing until the answer, unless it stopped offering it
+        let mut offerer_rtcp_mux = vec![];
+        if let Some(leg) = self.store.get_leg_mut(call_id_hashed, &req.from_tag) {
+          leg.rtcp_mux_flags = req.rtcp_mux.clone();
+          offerer_rtcp_mux = leg
+            .streams
+            .iter()
+            .zip(remote_sdp.media.iter())
+            .map(|(stream, media)| stream.rtcp_mux && media.rtcp_mux)
+            .collect();
+        }
+        self.set_leg_rtcp_mux(now, call_id_hashed, &req.from_tag, &offerer_rtcp_mux);
+        self.update_leg_remote(now, call_id_hashed, &req.from_tag, req.sdp, remote_addrs);
+        self.set_leg_local_sdp(call_id_hashed, &peer_tag, &local_sdp);
+        self.refresh_idle_timeout(now, call_id_hashed);
+        return Ok(local_sdp);
       }
+      return Err("Unknown from-tag".to_string());
+    }
+
+    let (remote_addrs, remote_sdp) = self.parse_remote_sdp(&req.sdp)?;
+    // Each side takes its interface from the direction, or the default one of its address family.
+    // The engine bridges the two interfaces, whatever their families.
+    let offer_interface = match &req.from_interface {
+      Some(name) => self.interface_by_name(name)?,
+      None => self.interface_by_family(Self::remote_family(&remote_addrs))?,
+    };
+    let answer_interface = match (&req.to_interface, req.address_family) {
+      (Some(name), _) => self.interface_by_name(name)?,
+      (None, Some(family)) => self.interface_by_family(family)?,
+      (None, None) => offer_interface,
+    };
+    let count = remote_addrs.len();
+    // the disabled m-lines keep port 0 on both sides
+    let enabled = Self::enabled_media(&remote_sdp);
+    let enabled_count = enabled.iter().filter(|enabled| **enabled).count();
+    let offer_ports = self.allocate_ports(offer_interface, enabled_count)?;
+    let answer_ports = match self.allocate_ports(answer_interface, enabled_count) {
+      Ok(ports) => ports,
       Err(e) => {
+        for port in offer_ports {
+          self.store.push_port(offer_interface, port);
+        }
         return Err(e);
       }
+    };
+    let spread = |ports: Vec<usize>| -> Vec<usize> {
+      let mut ports = ports.into_iter();
+      enabled
+        .iter()
+        .map(|enabled| match enabled {
+          true => ports.next().unwrap_or_default(),
+          false => 0,
+        })
+        .collect()
+    };
+    let (offer_ports, answer_ports) = (spread(offer_ports), spread(answer_ports));
+    let rtcp_mux: Vec<bool> = remote_sdp
+      .media
+      .iter()
+      .map(|media| Self::offer_rtcp_mux(&req.rtcp_mux, media.rtcp_mux))
+      .collect();
+    let local_sdp = self.local_sdp(
+      &remote_sdp.sdp, answer_interface, &answer_ports, &rtcp_mux, "", &req.options,
+    );
+
+    let created = unix_timestamp();
+    let mut offer_streams = Vec::with_capacity(count);
+    let mut answer_streams = Vec::with_capacity(count);
+    for (index, remote_addr) in remote_addrs.into_iter().enumerate() {
+      offer_streams.push(self.new_stream(
+        now, call_id_hashed, index, offer_interface, offer_ports[index], remote_addr,
+      ));
+      answer_streams.push(self.new_stream(now, call_id_hashed, index, answer_interface, answer_ports[index], None));
+    }
+    self.store.add_leg(
+      call_id_hashed,
+      &req.call_id,
+      Leg {
+        tag: req.from_tag,
+        branches: vec![],
+        created,
+        remote_sdp: req.sdp,
+        local_sdp: "".to_string(),
+        interface: offer_interface,
+        rtcp_mux_flags: req.rtcp_mux,
+        streams: offer_streams,
+      },
+    );
+    self.store.add_leg(
+      call_id_hashed,
+      &req.call_id,
+      Leg {
+        tag: "".to_string(),
+        branches: vec![],
+        created,
+        remote_sdp: "".to_string(),
+        local_sdp: local_sdp.clone(),
+        interface: answer_interface,
+        rtcp_mux_flags: vec![],
+        streams: answer_streams,
+      },
+    );
+    if let Some(call) = self.store.get_call_mut(call_id_hashed) {
+      call.final_deadline = self.final_timeout.map(|final_timeout| now + final_timeout);
+    }
+    self.refresh_idle_timeout(now, call_id_hashed);
+    Ok(local_sdp)
+  }
+
+  // The answer completes the leg the offer created for this side and returns the sdp advertising
+  // the ports allocated for the offerer. An answer for a known to-tag updates that leg in place.
+  // Its m-lines match the offer ones by position.
+  pub fn process_answer(&mut self, now: Instant, req: SdpExchange) -> Result<String, String> {
+    let call_id_hashed = Self::channel_build(&req.call_id);
+    self.cancel_delayed_delete(&req.call_id);
+    let call = self
+      .store
+      .get_call(call_id_hashed)
+      .ok_or("Unknown call-id".to_string())?;
+    let offer_leg = call.legs.get(&req.from_tag).ok_or("Unknown from-tag".to_string())?;
+    let to_tag = req.to_tag.unwrap_or_default();
+    let answer_tag = if call.legs.contains_key(&to_tag) {
+      to_tag.clone()
+    } else {
+      // a pending leg waits for its tag, otherwise a new or earlier branch takes over the answered leg
+      let branch_leg = call
+        .legs
+        .values()
+        .find(|leg| leg.branches.iter().any(|(branch, _)| *branch == to_tag));
+      match branch_leg
+        .map(|leg| &leg.tag)
+        .or_else(|| call.legs.keys().find(|tag| **tag != req.from_tag))
+      {
+        Some(tag) => tag.clone(),
+        None => return Err("Unknown to-tag".to_string()),
+      }
+    };
+    let offer_ports: Vec<usize> = offer_leg.streams.iter().map(|stream| stream.port).collect();
+    let offer_interface = offer_leg.interface;
+    let offer_local_sdp = offer_leg.local_sdp.clone();
+    let offered_rtcp_mux = Self::sdp_rtcp_mux(&offer_leg.remote_sdp);
+    let mut rtcp_mux_flags = offer_leg.rtcp_mux_flags.clone();
+    rtcp_mux_flags.extend(req.rtcp_mux.iter());
+    // the callee can only mux when the offer it got allowed it
+    let callee_offered_rtcp_mux = call
+      .legs
+      .get(&answer_tag)
+      .map(|leg| Self::sdp_rtcp_mux(&leg.local_sdp))
+      .unwrap_or_default();
+
+    let (remote_addrs, remote_sdp) = self.parse_remote_sdp(&req.sdp)?;
+    if remote_addrs.len() > offer_ports.len() {
+      return Err("Answer has more media than the offer".to_string());
+    }
+    let callee_rtcp_mux: Vec<bool> = remote_sdp
+      .media
+      .iter()
+      .enumerate()
+      .map(|(index, media)| media.rtcp_mux && callee_offered_rtcp_mux.get(index).copied().unwrap_or(false))
+      .collect();
+    let caller_rtcp_mux: Vec<bool> = callee_rtcp_mux
+      .iter()
+      .enumerate()
+      .map(|(index, answered)| {
+        let offered = offered_rtcp_mux.get(index).copied().unwrap_or(false);
+        Self::answer_rtcp_mux(&rtcp_mux_flags, offered, *answered)
+      })
+      .collect();
+    if answer_tag != to_tag {
+      self.store.rename_leg(call_id_hashed, &answer_tag, &to_tag);
+    }
+    // an m-line the callee rejects is closed on both sides, the answer can't enable a disabled one
+    let enabled: Vec<bool> = Self::enabled_media(&remote_sdp)
+      .into_iter()
+      .zip(offer_ports.iter())
+      .map(|(enabled, port)| enabled && *port != 0)
+      .collect();
+    for tag in [&req.from_tag, &to_tag] {
+      self.update_leg_ports(now, call_id_hashed, tag, &enabled)?;
+    }
+    let offer_ports = self.leg_ports(call_id_hashed, &req.from_tag);
+    let local_sdp = self.local_sdp(
+      &remote_sdp.sdp, offer_interface, &offer_ports, &caller_rtcp_mux, &offer_local_sdp, &req.options,
+    );
+    self.set_leg_local_sdp(call_id_hashed, &req.from_tag, &local_sdp);
+    self.set_leg_rtcp_mux(now, call_id_hashed, &req.from_tag, &caller_rtcp_mux);
+    self.set_leg_rtcp_mux(now, call_id_hashed, &to_tag, &callee_rtcp_mux);
+    self.update_leg_remote(now, call_id_hashed, &to_tag, req.sdp, remote_addrs);
+    self.refresh_idle_timeout(now, call_id_hashed);
+    Ok(local_sdp)
+  }
+
+  // Switch every stream of the call between the normal and the silent timeout following the hold
+  // state. The streams wait for media only once every leg has its remote: a ringing call has nothing
+  // to receive yet, and is only ended by a delete or the final timeout.
+  fn refresh_idle_timeout(&mut self, now: Instant, call_id: u64) {
+    let call = match self.store.get_call_mut(call_id) {
+      Some(call) => call,
+      None => return,
+    };
+    call.on_hold = call.legs.values().any(|leg| is_hold_sdp(&leg.remote_sdp));
+    let answered = call.legs.values().all(|leg| !leg.remote_sdp.is_empty());
+    let idle_timeout = match (answered, call.on_hold) {
+      (false, _) => None,
+      (true, true) => Some(self.silent_timeout),
+      (true, false) => Some(self.timeout),
+    };
+    if idle_timeout == call.idle_timeout {
+      return;
+    }
+    call.idle_timeout = idle_timeout;
+    let idle_timeout = match idle_timeout {
+      Some(idle_timeout) => idle_timeout,
+      None => return,
+    };
+    let tasks: Vec<TaskId> = call
+      .legs
+      .values()
+      .flat_map(|leg| leg.streams.iter().map(|stream| stream.task_id))
+      .collect();
+    for task_id in tasks {
+      self.send_to_task(now, task_id, RtpInput::IdleTimeout(idle_timeout));
     }
   }
 
   pub fn process_rpc_request<'a>(
     &mut self,
+    now: Instant,
     rpc: MediaRpcRequest,
   ) -> WorkerInnerOutput<'a, OwnerType, ExtOut, ChannelId, RtpEvent, SCfg> {
     match rpc.cmd {
-      MediaRpcCmd::Call(call_id, leg_id, sdp) => {
-        let res = self.new_leg(call_id, leg_id, sdp);
-        match res {
-          Ok(sdp) => WorkerInnerOutput::Ext(
-            true,
-            ExtOut::Rpc(MediaRpcResponse {
-              id: rpc.id,
-              res: crate::MediaRpcResult::Call(sdp),
-            }),
-          ),
-          Err(err) => WorkerInnerOutput::Ext(
-            true,
-            ExtOut::Rpc(MediaRpcResponse {
-              id: rpc.id,
-              res: crate::MediaRpcResult::Error(err),
-            }),
-          ),
-        }
+      MediaRpcCmd::Offer(req) => {
+        debug!("on rpc offer call {} from {}", req.call_id, req.from_tag);
+        let res = match self.process_offer(now, req) {
+          Ok(sdp) => crate::MediaRpcResult::Offer(sdp),
+          Err(err) => crate::MediaRpcResult::Error(err),
+        };
+        WorkerInnerOutput::Ext(true, ExtOut::Rpc(MediaRpcResponse { id: rpc.id, res }))
       }
-      MediaRpcCmd::End(call_id) => {
-        debug!("on rpc end call {}", call_id);
-        self.process_end_call(&call_id);
-        WorkerInnerOutput::Ext(
-          true,
-          ExtOut::Rpc(MediaRpcResponse {
-            id: rpc.id,
-            res: crate::MediaRpcResult::End,
-          }),
-        )
+      MediaRpcCmd::Answer(req) => {
+        debug!("on rpc answer call {} to {:?}", req.call_id, req.to_tag);
+        let res = match self.process_answer(now, req) {
+          Ok(sdp) => crate::MediaRpcResult::Answer(sdp),
+          Err(err) => crate::MediaRpcResult::Error(err),
+        };
+        WorkerInnerOutput::Ext(true, ExtOut::Rpc(MediaRpcResponse { id: rpc.id, res }))
+      }
+      MediaRpcCmd::End(req) => {
+        debug!("on rpc end call {} to {:?}", req.call_id, req.to_tag);
+        let res = match self.process_delete(now, req) {
+          Ok(()) => crate::MediaRpcResult::End,
+          Err(err) => crate::MediaRpcResult::Error(err),
+        };
+        WorkerInnerOutput::Ext(true, ExtOut::Rpc(MediaRpcResponse { id: rpc.id, res }))
+      }
+      MediaRpcCmd::Query(call_id) => {
+        debug!("on rpc query call {}", call_id);
+        let res = match self.store.get_call(Self::channel_build(&call_id)) {
+          Some(call) => crate::MediaRpcResult::Query(call.stats()),
+          None => crate::MediaRpcResult::Error("Unknown call-id".to_string()),
+        };
+        WorkerInnerOutput::Ext(true, ExtOut::Rpc(MediaRpcResponse { id: rpc.id, res }))
       }
+      MediaRpcCmd::List(limit) => WorkerInnerOutput::Ext(
+        true,
+        ExtOut::Rpc(MediaRpcResponse {
+          id: rpc.id,
+          res: crate::MediaRpcResult::List(self.store.list_calls(limit)),
+        }),
+      ),
       MediaRpcCmd::Ping => WorkerInnerOutput::Ext(
         true,
         ExtOut::Rpc(MediaRpcResponse {
@@ -184,47 +817,183 @@ impl RtpEngineMediaWorker {
     }
   }
 
-  pub fn process_end_call(&mut self, call_id: &str) {
-    let hashed = Self::channel_build(call_id);
-    let tasks = self.store.get_call(hashed);
-    if let Some(tasks) = tasks {
-      for task in tasks.iter() {
-        let backend = self.store.get_slot_by_task(task);
-        if let Some(slot) = backend {
-          self.output.push_back(WorkerInnerOutput::Net(
-            OwnerType::System,
-            BackendOutgoing::UdpUnlisten { slot: *slot },
-          ));
+  // Without to-tag the whole call is deleted. With a to-tag only that branch goes away, and the call
+  // ends with it when no other leg is left to relay to.
+  pub fn process_delete(&mut self, now: Instant, req: EndRequest) -> Result<(), String> {
+    let call_id_hashed = Self::channel_build(&req.call_id);
+    if self.store.get_call(call_id_hashed).is_none() {
+      return Err("Unknown call-id".to_string());
+    }
+    if let Some(to_tag) = &req.to_tag {
+      if !self.store.has_branch(call_id_hashed, to_tag) {
+        return Err("Unknown to-tag".to_string());
+      }
+    }
+    if let Some(delay) = req.delay.filter(|delay| *delay > 0) {
+      debug!("delete of call {} delayed by {}s", req.call_id, delay);
+      self
+        .delayed_deletes
+        .push((now + Duration::from_secs(delay), EndRequest { delay: None, ..req }));
+      return Ok(());
+    }
+
+    match req.to_tag {
+      Some(to_tag) => {
+        match self.store.remove_branch(call_id_hashed, &to_tag) {
+          // the leg may be back on an earlier branch, its streams follow that remote
+          Some(tag) => self.reapply_leg_remote(now, call_id_hashed, &tag),
+          None => self.process_end_leg(now, call_id_hashed, &to_tag),
         }
-        match task {
-          TaskId::Rtp(index) => {
-            self.rtp_group.remove_task(*index);
+        if self
+          .store
+          .get_call(call_id_hashed)
+          .map_or(false, |call| call.legs.len() < 2)
+        {
+          self.process_end_call(now, &req.call_id);
+        }
+      }
+      None => self.process_end_call(now, &req.call_id),
+    }
+    Ok(())
+  }
+
+  fn reapply_leg_remote(&mut self, now: Instant, call_id: u64, tag: &str) {
+    let sdp = match self.store.get_leg(call_id, tag) {
+      Some(leg) => leg.remote_sdp.clone(),
+      None => return,
+    };
+    match self.parse_remote_sdp(&sdp) {
+      Ok((remote_addrs, _)) => {
+        self.update_leg_remote(now, call_id, tag, sdp, remote_addrs);
+        self.refresh_idle_timeout(now, call_id);
+      }
+      Err(err) => debug!("remote sdp of leg {} not applied: {}", tag, err),
+    }
+  }
+
+  fn cancel_delayed_delete(&mut self, call_id: &str) {
+    self.delayed_deletes.retain(|(_, req)| req.call_id != call_id);
+  }
+
+  fn process_delayed_deletes(&mut self, now: Instant) {
+    if self.delayed_deletes.iter().all(|(at, _)| *at > now) {
+      return;
+    }
+    let (expired, pending) = std::mem::take(&mut self.delayed_deletes)
+      .into_iter()
+      .partition(|(at, _)| *at <= now);
+    self.delayed_deletes = pending;
+    for (_, req) in expired {
+      if let Err(err) = self.process_delete(now, req) {
+        debug!("delayed delete skipped: {}", err);
+      }
+    }
+  }
+
+  fn process_timeouts(&mut self, now: Instant) {
+    for call_id in std::mem::take(&mut self.idle_calls) {
+      if !self.store.is_call_idle(call_id) {
+        continue;
+      }
+      if let Some(call) = self.store.get_call(call_id) {
+        let reason = if call.on_hold {
+          CallEndReason::SilentTimeout
+        } else {
+          CallEndReason::Timeout
+        };
+        let call_id = call.call_id.clone();
+        self.end_call_with_reason(now, call_id, reason);
+      }
+    }
+
+    if self.final_timeout.is_some() && self.final_timeout_check_at.map_or(true, |at| at <= now) {
+      self.final_timeout_check_at = Some(now + Duration::from_secs(1));
+      for call_id in self.store.calls_past_deadline(now) {
+        self.end_call_with_reason(now, call_id, CallEndReason::FinalTimeout);
+      }
+    }
+  }
+
+  fn end_call_with_reason(&mut self, now: Instant, call_id: String, reason: CallEndReason) {
+    debug!("end call {} by {:?}", call_id, reason);
+    self.process_end_call(now, &call_id);
+    self.cancel_delayed_delete(&call_id);
+    self
+      .output
+      .push_back(WorkerInnerOutput::Ext(true, ExtOut::CallEnded(call_id, reason)));
+  }
+
+  pub fn process_end_call(&mut self, now: Instant, call_id: &str) {
+    let hashed = Self::channel_build(call_id);
+    if let Some(call) = self.store.remove_call(hashed) {
+      for (_, leg) in call.legs {
+        self.release_leg(now, leg);
+      }
+    }
+  }
+
+  pub fn process_end_leg(&mut self, now: Instant, call_id: u64, tag: &str) {
+    if let Some(leg) = self.store.remove_leg(call_id, tag) {
+      self.release_leg(now, leg);
+    }
+  }
+
+  fn release_leg(&mut self, now: Instant, leg: Leg) {
+    debug!("release leg {} with {} streams", leg.tag, leg.streams.len());
+    for stream in leg.streams {
+      for slot in stream.slot.into_iter().chain(stream.rtcp_slot) {
+        self.output.push_back(WorkerInnerOutput::Net(
+          OwnerType::System,
+          BackendOutgoing::UdpUnlisten { slot },
+        ));
+      }
+      // the task is removed once it left the bus, so a reused index never keeps its subscription
+      match stream.task_id {
+        TaskId::Rtp(index) => {
+          let out = self.rtp_group.on_event(now, index, RtpInput::Shutdown);
+          if let Some(out) = out.and_then(|out| self.process_rtp_out(index, out)) {
+            self.output.push_back(out);
           }
         }
       }
+      if stream.port != 0 {
+        self.store.release_port(now, leg.interface, stream.port);
+      }
     }
-    self.store.remove_call(hashed);
   }
 
   pub fn process_rtp_out<'a>(
     &mut self,
-    _now: Instant,
     index: usize,
     out: RtpOutput,
   ) -> Option<(WorkerInnerOutput<'a, OwnerType, ExtOut, ChannelId, RtpEvent, SCfg>)> {
     let owner = OwnerType::Rtp(index.into());
     match out {
-      RtpOutput::Destroy(port) => {
-        self.store.push_port(port);
+      // the ports go back to the pool with the leg
+      RtpOutput::Destroy(_port) => {
+        self.rtp_group.remove_task(index);
         Some(WorkerInnerOutput::Destroy(owner))
       }
+      RtpOutput::Stats(ingress, egress) => {
+        self.store.update_stream_stats(&TaskId::Rtp(index), ingress, egress);
+        None
+      }
+      RtpOutput::Idle(idle) => {
+        // the call is ended from the next tick, outside of the task group iteration
+        if let Some(call_id) = self.store.set_stream_idle(&TaskId::Rtp(index), idle) {
+          if idle && self.store.is_call_idle(call_id) {
+            self.idle_calls.push(call_id);
+          }
+        }
+        None
+      }
       RtpOutput::Forward { to, data } => {
-        let backend = self.store.get_backend(&to.to_string());
+        let backend = self.store.get_slot_by_task(&TaskId::Rtp(index));
         if let Some(slot) = backend {
           Some(WorkerInnerOutput::Net(
             OwnerType::System,
             BackendOutgoing::UdpPacket {
-              slot: *slot,
+              slot,
               to,
               data: data.into(),
             },
@@ -233,6 +1002,17 @@ impl RtpEngineMediaWorker {
           None
         }
       }
+      RtpOutput::ForwardRtcp { to, data } => {
+        let slot = self.store.get_rtcp_slot_by_task(&TaskId::Rtp(index))?;
+        Some(WorkerInnerOutput::Net(
+          OwnerType::System,
+          BackendOutgoing::UdpPacket {
+            slot,
+            to,
+            data: data.into(),
+          },
+        ))
+      }
       RtpOutput::Bus(control) => Some(WorkerInnerOutput::Bus(BusControl::Channel(
         owner,
         control.convert_into(),
@@ -247,10 +1027,25 @@ impl WorkerInner<OwnerType, ExtInput, ExtOut, ChannelId, RtpEvent, Config, SCfg>
       worker,
       rtp_group: RtpTaskGroup::default(),
       output: VecDeque::new(),
-      store: CallMediaStore::new(cfg.port_range),
+      store: CallMediaStore::new(
+        &cfg
+          .interfaces
+          .iter()
+          .map(|interface| interface.port_range.clone())
+          .collect::<Vec<_>>(),
+        cfg.port_quarantine,
+      ),
       switcher: TaskSwitcher::new(0),
       shutdown: false,
-      ip: cfg.ip,
+      drained: None,
+      stream_seq: 0,
+      delayed_deletes: Vec::new(),
+      timeout: cfg.timeout,
+      silent_timeout: cfg.silent_timeout,
+      final_timeout: cfg.final_timeout,
+      idle_calls: Vec::new(),
+      final_timeout_check_at: None,
+      interfaces: cfg.interfaces,
     }
   }
 
@@ -270,16 +1065,16 @@ impl WorkerInner<OwnerType, ExtInput, ExtOut, ChannelId, RtpEvent, Config, SCfg>
     event: sans_io_runtime::WorkerInnerInput<'a, OwnerType, ExtInput, ChannelId, RtpEvent>,
   ) -> Option<WorkerInnerOutput<'a, OwnerType, ExtOut, ChannelId, RtpEvent, SCfg>> {
     match event {
-      WorkerInnerInput::Net(_owner, BackendIncoming::UdpListenResult { bind: _, result }) => match result {
+      WorkerInnerInput::Net(_owner, BackendIncoming::UdpListenResult { bind, result }) => match result {
         Ok((addr, slot)) => {
           debug!("save {} by addr {}", slot, addr.to_string());
-          let task = self.store.get_task_by_addr(&addr.to_string());
-          match task {
-            Some(task_id) => {
-              self.store.save_slot_task(task_id.clone(), slot);
-              None
-            }
-            None => None,
+          match self.store.save_slot(&bind, slot) {
+            Some(_) => None,
+            // the leg was released before its socket was ready
+            None => Some(WorkerInnerOutput::Net(
+              OwnerType::System,
+              BackendOutgoing::UdpUnlisten { slot },
+            )),
           }
         }
         Err(e) => {
@@ -287,29 +1082,25 @@ impl WorkerInner<OwnerType, ExtInput, ExtOut, ChannelId, RtpEvent, Config, SCfg>
           None
         }
       },
-      WorkerInnerInput::Net(_owner, BackendIncoming::UdpListenResult { bind: _, result }) => match result {
-        Ok((addr, slot)) => {
-          debug!("save {} by addr {}", slot, addr.to_string());
-          self.store.add_backend(addr.to_string(), slot);
-          None
-        }
-        Err(e) => {
-          error!("error when bind udp listener {}", e);
-          None
-        }
-      },
-      WorkerInnerInput::Net(_owner, BackendIncoming::UdpPacket { slot, from, data }) => {
-        self.store.add_backend(from.to_string(), slot);
-        let addr_str = from.to_string();
-        let task = self.store.get_task(&addr_str);
+      WorkerInnerInput::Net(_owner, BackendIncoming::UdpPacket { slot, from: _, data }) => {
+        let task = self.store.get_task_by_slot(slot);
         match task {
-          Some(TaskId::Rtp(index)) => {
-            debug!("task index {}, send event to task", *index);
+          Some((TaskId::Rtp(index), SocketKind::Rtcp)) => {
             let out = self
               .rtp_group
-              .on_event(now, *index, RtpInput::UdpPacket { data: data.freeze() });
+              .on_event(now, index, RtpInput::RtcpPacket { data: data.freeze() });
             match out {
-              Some(out) => self.process_rtp_out(now, *index, out),
+              Some(out) => self.process_rtp_out(index, out),
+              None => None,
+            }
+          }
+          Some((TaskId::Rtp(index), SocketKind::Rtp)) => {
+            debug!("task index {}, send event to task", index);
+            let out = self
+              .rtp_group
+              .on_event(now, index, RtpInput::UdpPacket { data: data.freeze() });
+            match out {
+              Some(out) => self.process_rtp_out(index, out),
               None => None,
             }
           }
@@ -323,19 +1114,28 @@ impl WorkerInner<OwnerType, ExtInput, ExtOut, ChannelId, RtpEvent, Config, SCfg>
             owner.index(),
             RtpInput::Bus {
               from: packet.from,
+              rtcp: packet.rtcp,
               data: Buffer::from(packet.data),
             },
           );
           match out {
-            Some(out) => self.process_rtp_out(now, owner.index(), out),
+            Some(out) => self.process_rtp_out(owner.index(), out),
             None => None,
           }
         }
         _ => None,
       },
       WorkerInnerInput::Ext(input) => match input {
-        ExtInput::Rpc(req) => Some(self.process_rpc_request(req)),
-        _ => None,
+        ExtInput::Rpc(req) => Some(self.process_rpc_request(now, *req)),
+        ExtInput::Drain => {
+          debug!(
+            "worker {} draining {} calls",
+            self.worker,
+            self.store.list_calls(None).len()
+          );
+          self.drained.get_or_insert(false);
+          None
+        }
       },
       _ => None,
     }
@@ -345,12 +1145,23 @@ impl WorkerInner<OwnerType, ExtInput, ExtOut, ChannelId, RtpEvent, Config, SCfg>
     &mut self,
     now: std::time::Instant,
   ) -> Option<WorkerInnerOutput<'a, OwnerType, ExtOut, ChannelId, RtpEvent, SCfg>> {
+    self.process_delayed_deletes(now);
+    self.process_timeouts(now);
+    self.store.reclaim_ports(now);
+    if self.drained == Some(false) && self.store.is_empty() {
+      self.drained = Some(true);
+      self
+        .output
+        .push_back(WorkerInnerOutput::Ext(true, ExtOut::Drained(self.worker)));
+    }
     if let Some(o) = self.output.pop_front() {
       return Some(o.into());
     }
 
-    if let Some((index, out)) = self.rtp_group.on_tick(now) {
-      return self.process_rtp_out(now, index, out);
+    while let Some((index, out)) = self.rtp_group.on_tick(now) {
+      if let Some(out) = self.process_rtp_out(index, out) {
+        return Some(out);
+      }
     }
 
     None
@@ -360,8 +1171,10 @@ impl WorkerInner<OwnerType, ExtInput, ExtOut, ChannelId, RtpEvent, Config, SCfg>
     &mut self,
     now: std::time::Instant,
   ) -> Option<WorkerInnerOutput<'a, OwnerType, ExtOut, ChannelId, RtpEvent, SCfg>> {
-    if let Some((index, out)) = self.rtp_group.on_tick(now) {
-      return self.process_rtp_out(now, index, out);
+    while let Some((index, out)) = self.rtp_group.on_tick(now) {
+      if let Some(out) = self.process_rtp_out(index, out) {
+        return Some(out);
+      }
     }
     self.output.pop_front()
   }
@@ -370,6 +1183,699 @@ impl WorkerInner<OwnerType, ExtInput, ExtOut, ChannelId, RtpEvent, Config, SCfg>
     &mut self,
     now: std::time::Instant,
   ) -> Option<WorkerInnerOutput<'a, OwnerType, ExtOut, ChannelId, RtpEvent, SCfg>> {
-    None
+    // the calls still running are ended, releasing their sockets
+    if !self.shutdown {
+      self.shutdown = true;
+      for call_id in self.store.list_calls(None) {
+        self.end_call_with_reason(now, call_id, CallEndReason::Shutdown);
+      }
+    }
+    self.output.pop_front()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::{Duration, Instant};
+
+  use sans_io_runtime::{
+    backend::BackendOutgoing, BusChannelControl, BusControl, WorkerInner, WorkerInnerInput, WorkerInnerOutput,
+  };
+
+  use crate::{
+    AddressFamily, CallStats, EndRequest, MediaRpcCmd, MediaRpcRequest, MediaRpcResult, Replace, RtcpMux, SdpExchange,
+  };
+
+  use super::{
+    CallEndReason, ChannelId, Config, ExtInput, ExtOut, Interface, OwnerType, PortRange, RtpEngineMediaWorker,
+    RtpEvent, SCfg,
+  };
+
+  type Output = WorkerInnerOutput<'static, OwnerType, ExtOut, ChannelId, RtpEvent, SCfg>;
+
+  const REMOTE_SDP: &str = "v=0\r\no=- 1 1 IN IP4 192.168.1.10\r\ns=-\r\nc=IN IP4 192.168.1.10\r\nt=0 0\r\nm=audio 4000 RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\n";
+
+  // room for `streams` streams
+  fn interface(name: &str, bind: &str, advertised: &str, min: usize, streams: usize) -> Interface {
+    Interface {
+      name: name.to_string(),
+      bind: bind.parse().unwrap(),
+      advertised: advertised.to_string(),
+      port_range: PortRange {
+        min,
+        max: min + 2 * streams,
+      },
+    }
+  }
+
+  // room for the two streams of one call
+  fn config() -> Config {
+    Config {
+      interfaces: vec![interface("default", "127.0.0.1", "10.0.0.1", 10000, 2)],
+      timeout: Duration::from_secs(60),
+      silent_timeout: Duration::from_secs(3600),
+      final_timeout: None,
+      port_quarantine: Duration::from_secs(10),
+    }
+  }
+
+  fn worker(final_timeout: Option<Duration>) -> RtpEngineMediaWorker {
+    RtpEngineMediaWorker::build(
+      0,
+      Config {
+        final_timeout,
+        ..config()
+      },
+    )
+  }
+
+  fn exchange(from_tag: &str, to_tag: Option<&str>, sdp: &str) -> SdpExchange {
+    SdpExchange {
+      call_id: "call-1".to_string(),
+      from_tag: from_tag.to_string(),
+      to_tag: to_tag.map(|tag| tag.to_string()),
+      sdp: sdp.to_string(),
+      rtcp_mux: vec![],
+      address_family: None,
+      from_interface: None,
+      to_interface: None,
+      options: Default::default(),
+    }
+  }
+
+  fn end(to_tag: Option<&str>, delay: Option<u64>) -> MediaRpcCmd {
+    MediaRpcCmd::End(EndRequest {
+      call_id: "call-1".to_string(),
+      from_tag: None,
+      to_tag: to_tag.map(|tag| tag.to_string()),
+      delay,
+    })
+  }
+
+  fn pop_all(worker: &mut RtpEngineMediaWorker, now: Instant, first: Option<Output>) -> Vec<Output> {
+    let mut outputs: Vec<Output> = first.into_iter().collect();
+    while let Some(out) = worker.pop_output(now) {
+      outputs.push(out);
+    }
+    outputs
+  }
+
+  // the result of the command and everything else the worker output with it
+  fn rpc(worker: &mut RtpEngineMediaWorker, now: Instant, cmd: MediaRpcCmd) -> (MediaRpcResult, Vec<Output>) {
+    let req = MediaRpcRequest {
+      id: "rpc-1".to_string(),
+      cmd,
+    };
+    let first = worker.on_event(now, WorkerInnerInput::Ext(ExtInput::Rpc(Box::new(req))));
+    let mut res = None;
+    let mut outputs = vec![];
+    for out in pop_all(worker, now, first) {
+      match out {
+        WorkerInnerOutput::Ext(_, ExtOut::Rpc(rpc)) => res = Some(rpc.res),
+        out => outputs.push(out),
+      }
+    }
+    (res.expect("rpc result"), outputs)
+  }
+
+  fn tick(worker: &mut RtpEngineMediaWorker, now: Instant) -> Vec<Output> {
+    let first = worker.on_tick(now);
+    pop_all(worker, now, first)
+  }
+
+  fn query(worker: &mut RtpEngineMediaWorker, now: Instant) -> Option<CallStats> {
+    match rpc(worker, now, MediaRpcCmd::Query("call-1".to_string())).0 {
+      MediaRpcResult::Query(stats) => Some(stats),
+      _ => None,
+    }
+  }
+
+  fn ended(outputs: &[Output]) -> Vec<CallEndReason> {
+    outputs
+      .iter()
+      .filter_map(|out| match out {
+        WorkerInnerOutput::Ext(_, ExtOut::CallEnded(call_id, reason)) if call_id == "call-1" => Some(*reason),
+        _ => None,
+      })
+      .collect()
+  }
+
+  fn start_call(worker: &mut RtpEngineMediaWorker, now: Instant) -> String {
+    let (res, _) = rpc(worker, now, MediaRpcCmd::Offer(exchange("from", None, REMOTE_SDP)));
+    let sdp = match res {
+      MediaRpcResult::Offer(sdp) => sdp,
+      res => panic!("offer failed: {:?}", res),
+    };
+    let answer = REMOTE_SDP.replace("192.168.1.10", "192.168.1.20");
+    let (res, _) = rpc(worker, now, MediaRpcCmd::Answer(exchange("from", Some("to"), &answer)));
+    assert!(matches!(res, MediaRpcResult::Answer(_)));
+    sdp
+  }
+
+  fn listens(outputs: &[Output]) -> usize {
+    outputs
+      .iter()
+      .filter(|out| matches!(out, WorkerInnerOutput::Net(_, BackendOutgoing::UdpListen { .. })))
+      .count()
+  }
+
+  fn listen_addrs(outputs: &[Output]) -> Vec<String> {
+    outputs
+      .iter()
+      .filter_map(|out| match out {
+        WorkerInnerOutput::Net(_, BackendOutgoing::UdpListen { addr, .. }) => Some(addr.to_string()),
+        _ => None,
+      })
+      .collect()
+  }
+
+  fn media_port(sdp: &str) -> &str {
+    sdp
+      .split("m=audio ")
+      .nth(1)
+      .and_then(|media| media.split(' ').next())
+      .expect("audio m-line")
+  }
+
+  #[test]
+  fn reoffer_keeps_ports() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    let (res, outputs) = rpc(&mut worker, now, MediaRpcCmd::Offer(exchange("from", None, REMOTE_SDP)));
+    let offer = match res {
+      MediaRpcResult::Offer(sdp) => sdp,
+      res => panic!("offer failed: {:?}", res),
+    };
+    // rtp and rtcp sockets of both legs
+    assert_eq!(listens(&outputs), 4);
+
+    let moved = REMOTE_SDP.replace("4000", "4010");
+    let (res, outputs) = rpc(&mut worker, now, MediaRpcCmd::Offer(exchange("from", None, &moved)));
+    match res {
+      MediaRpcResult::Offer(sdp) => assert_eq!(media_port(&sdp), media_port(&offer)),
+      res => panic!("re-offer failed: {:?}", res),
+    }
+    assert_eq!(listens(&outputs), 0);
+    assert_eq!(worker.tasks(), 2);
+  }
+
+  const VIDEO_SDP: &str = "m=video 4002 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\n";
+
+  fn offered_sdp(res: MediaRpcResult) -> String {
+    match res {
+      MediaRpcResult::Offer(sdp) | MediaRpcResult::Answer(sdp) => sdp,
+      res => panic!("sdp exchange failed: {:?}", res),
+    }
+  }
+
+  #[test]
+  fn rejected_video() {
+    let now = Instant::now();
+    let mut worker = RtpEngineMediaWorker::build(
+      0,
+      Config {
+        interfaces: vec![interface("default", "127.0.0.1", "10.0.0.1", 10000, 4)],
+        ..config()
+      },
+    );
+    let offer = format!("{}{}", REMOTE_SDP, VIDEO_SDP);
+    let (res, outputs) = rpc(&mut worker, now, MediaRpcCmd::Offer(exchange("from", None, &offer)));
+    assert!(offered_sdp(res).contains("m=video 1000"));
+    assert_eq!(listens(&outputs), 8);
+
+    let answer = format!("{}{}", REMOTE_SDP, VIDEO_SDP.replace("4002", "0")).replace("192.168.1.10", "192.168.1.20");
+    let (res, _) = rpc(
+      &mut worker,
+      now,
+      MediaRpcCmd::Answer(exchange("from", Some("to"), &answer)),
+    );
+    let sdp = offered_sdp(res);
+    assert!(sdp.contains("m=audio 1000"));
+    assert!(sdp.contains("m=video 0 RTP/AVP 96\r\n"));
+
+    // the video ports of both legs are back after the quarantine
+    tick(&mut worker, now + Duration::from_secs(10));
+    assert!(worker.store.next_port(0).is_some());
+    assert!(worker.store.next_port(0).is_some());
+    assert_eq!(worker.store.next_port(0), None);
+  }
+
+  #[test]
+  fn disabled_video_offered() {
+    let now = Instant::now();
+    let mut worker = RtpEngineMediaWorker::build(
+      0,
+      Config {
+        interfaces: vec![interface("default", "127.0.0.1", "10.0.0.1", 10000, 4)],
+        ..config()
+      },
+    );
+    let offer = format!("{}{}", REMOTE_SDP, VIDEO_SDP.replace("4002", "0"));
+    let (res, outputs) = rpc(&mut worker, now, MediaRpcCmd::Offer(exchange("from", None, &offer)));
+    assert!(offered_sdp(res).contains("m=video 0 RTP/AVP 96\r\n"));
+    assert_eq!(listens(&outputs), 4);
+
+    // a re-offer enabling the video gives it ports on both legs
+    let offer = format!("{}{}", REMOTE_SDP, VIDEO_SDP);
+    let (res, outputs) = rpc(&mut worker, now, MediaRpcCmd::Offer(exchange("from", None, &offer)));
+    assert!(offered_sdp(res).contains("m=video 1000"));
+    assert_eq!(listens(&outputs), 4);
+  }
+
+  #[test]
+  fn rtcp_mux_flags() {
+    let offer = RtpEngineMediaWorker::offer_rtcp_mux;
+    assert!(offer(&[], true));
+    assert!(!offer(&[], false));
+    assert!(offer(&[RtcpMux::Offer], false));
+    assert!(!offer(&[RtcpMux::Demux], true));
+
+    // offered, answered
+    let answer = RtpEngineMediaWorker::answer_rtcp_mux;
+    assert!(answer(&[], true, true));
+    assert!(!answer(&[], true, false));
+    assert!(!answer(&[], false, true));
+    assert!(answer(&[RtcpMux::Accept], true, false));
+    assert!(answer(&[RtcpMux::Demux], true, false));
+    assert!(!answer(&[RtcpMux::Reject], true, true));
+    assert!(!answer(&[RtcpMux::Accept], false, false));
+  }
+
+  #[test]
+  fn rtcp_mux_demuxed() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    let muxed = format!("{}a=rtcp-mux\r\n", REMOTE_SDP);
+    let mut req = exchange("from", None, &muxed);
+    req.rtcp_mux = vec![RtcpMux::Demux];
+    let sdp = offered_sdp(rpc(&mut worker, now, MediaRpcCmd::Offer(req)).0);
+    assert!(!sdp.contains("a=rtcp-mux"));
+    assert!(sdp.contains("a=rtcp:10003\r\n"));
+
+    // the callee can't mux what it was not offered, the caller still gets its muxing
+    let answer = muxed.replace("192.168.1.10", "192.168.1.20");
+    let sdp = offered_sdp(
+      rpc(
+        &mut worker,
+        now,
+        MediaRpcCmd::Answer(exchange("from", Some("to"), &answer)),
+      )
+      .0,
+    );
+    assert!(sdp.contains("a=rtcp:10000\r\na=rtcp-mux\r\n"));
+  }
+
+  #[test]
+  fn leg_interfaces() {
+    let now = Instant::now();
+    let mut worker = RtpEngineMediaWorker::build(
+      0,
+      Config {
+        interfaces: vec![
+          interface("internal", "127.0.0.1", "10.0.0.1", 10000, 1),
+          interface("external", "127.0.0.2", "203.0.113.1", 20000, 1),
+        ],
+        ..config()
+      },
+    );
+    let mut req = exchange("from", None, REMOTE_SDP);
+    req.from_interface = Some("internal".to_string());
+    req.to_interface = Some("external".to_string());
+    let (res, outputs) = rpc(&mut worker, now, MediaRpcCmd::Offer(req));
+    let sdp = offered_sdp(res);
+    assert!(sdp.contains("c=IN IP4 203.0.113.1\r\n"));
+    assert_eq!(media_port(&sdp), "20000");
+    assert_eq!(
+      listen_addrs(&outputs),
+      vec!["127.0.0.1:10000", "127.0.0.1:10001", "127.0.0.2:20000", "127.0.0.2:20001"]
+    );
+
+    let answer = REMOTE_SDP.replace("192.168.1.10", "203.0.113.20");
+    let sdp = offered_sdp(
+      rpc(
+        &mut worker,
+        now,
+        MediaRpcCmd::Answer(exchange("from", Some("to"), &answer)),
+      )
+      .0,
+    );
+    assert!(sdp.contains("c=IN IP4 10.0.0.1\r\n"));
+    assert_eq!(media_port(&sdp), "10000");
+  }
+
+  #[test]
+  fn address_family_bridged() {
+    let now = Instant::now();
+    let mut worker = RtpEngineMediaWorker::build(
+      0,
+      Config {
+        interfaces: vec![
+          interface("v4", "127.0.0.1", "10.0.0.1", 10000, 1),
+          interface("v6", "::1", "2001:db8::1", 20000, 1),
+        ],
+        ..config()
+      },
+    );
+    let mut req = exchange("from", None, REMOTE_SDP);
+    req.address_family = Some(AddressFamily::Ipv6);
+    let (res, outputs) = rpc(&mut worker, now, MediaRpcCmd::Offer(req));
+    let sdp = offered_sdp(res);
+    assert!(sdp.contains("c=IN IP6 2001:db8::1\r\n"));
+    assert_eq!(media_port(&sdp), "20000");
+    assert_eq!(
+      listen_addrs(&outputs),
+      vec!["127.0.0.1:10000", "127.0.0.1:10001", "[::1]:20000", "[::1]:20001"]
+    );
+
+    // the v6 answer goes back to the caller over v4
+    let answer = REMOTE_SDP.replace("IN IP4 192.168.1.10", "IN IP6 2001:db8::20");
+    let sdp = offered_sdp(
+      rpc(
+        &mut worker,
+        now,
+        MediaRpcCmd::Answer(exchange("from", Some("to"), &answer)),
+      )
+      .0,
+    );
+    assert!(sdp.contains("c=IN IP4 10.0.0.1\r\n"));
+    assert_eq!(media_port(&sdp), "10000");
+    let stats = query(&mut worker, now).expect("call");
+    let callee = stats.legs.iter().find(|leg| leg.tag == "to").expect("callee leg");
+    assert_eq!(callee.remote_addr, "[2001:db8::20]:4000");
+  }
+
+  #[test]
+  fn unknown_interface_refused() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    let mut req = exchange("from", None, REMOTE_SDP);
+    req.to_interface = Some("other".to_string());
+    let (res, outputs) = rpc(&mut worker, now, MediaRpcCmd::Offer(req));
+    assert_eq!(res, MediaRpcResult::Error("Unknown interface other".to_string()));
+    assert_eq!(listens(&outputs), 0);
+    assert!(query(&mut worker, now).is_none());
+  }
+
+  #[test]
+  fn drain() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    start_call(&mut worker, now);
+    assert!(worker.on_event(now, WorkerInnerInput::Ext(ExtInput::Drain)).is_none());
+
+    let mut req = exchange("from", None, REMOTE_SDP);
+    req.call_id = "call-2".to_string();
+    let (res, outputs) = rpc(&mut worker, now, MediaRpcCmd::Offer(req));
+    assert_eq!(
+      res,
+      MediaRpcResult::Error("Shutting down, no new call is accepted".to_string())
+    );
+    assert_eq!(listens(&outputs), 0);
+    // the running call goes on
+    let (res, _) = rpc(
+      &mut worker,
+      now,
+      MediaRpcCmd::Offer(exchange("from", Some("to"), REMOTE_SDP)),
+    );
+    assert!(matches!(res, MediaRpcResult::Offer(_)));
+
+    let drained = |outputs: Vec<Output>| {
+      outputs
+        .iter()
+        .filter(|out| matches!(out, WorkerInnerOutput::Ext(_, ExtOut::Drained(0))))
+        .count()
+    };
+    assert_eq!(drained(tick(&mut worker, now)), 0);
+    rpc(&mut worker, now, end(None, None));
+    assert_eq!(drained(tick(&mut worker, now)), 1);
+    assert_eq!(drained(tick(&mut worker, now)), 0);
+  }
+
+  #[test]
+  fn stripped_codecs() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    let offer = REMOTE_SDP.replace("RTP/AVP 0", "RTP/AVP 0 8 9");
+    let mut req = exchange("from", None, &offer);
+    req.options.codec.strip = vec!["PCMA".to_string()];
+    let sdp = offered_sdp(rpc(&mut worker, now, MediaRpcCmd::Offer(req)).0);
+    assert!(sdp.contains("RTP/AVP 0 9\r\n"));
+
+    let answer = offer.replace("192.168.1.10", "192.168.1.20");
+    let mut req = exchange("from", Some("to"), &answer);
+    req.options.codec.mask = vec!["G722".to_string()];
+    let sdp = offered_sdp(rpc(&mut worker, now, MediaRpcCmd::Answer(req)).0);
+    assert!(sdp.contains("RTP/AVP 0 8\r\n"));
+  }
+
+  #[test]
+  fn offer_from_unknown_tag_refused() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    start_call(&mut worker, now);
+
+    let (res, outputs) = rpc(
+      &mut worker,
+      now,
+      MediaRpcCmd::Offer(exchange("other", None, REMOTE_SDP)),
+    );
+    assert_eq!(res, MediaRpcResult::Error("Unknown from-tag".to_string()));
+    assert!(outputs.is_empty());
+    assert_eq!(query(&mut worker, now).map(|stats| stats.legs.len()), Some(2));
+  }
+
+  #[test]
+  fn delayed_delete() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    start_call(&mut worker, now);
+
+    assert_eq!(rpc(&mut worker, now, end(None, Some(5))).0, MediaRpcResult::End);
+    tick(&mut worker, now + Duration::from_secs(4));
+    assert!(query(&mut worker, now).is_some());
+    tick(&mut worker, now + Duration::from_secs(5));
+    assert!(query(&mut worker, now).is_none());
+  }
+
+  #[test]
+  fn reoffer_cancels_delayed_delete() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    start_call(&mut worker, now);
+
+    rpc(&mut worker, now, end(None, Some(5)));
+    let later = now + Duration::from_secs(1);
+    let (res, _) = rpc(
+      &mut worker,
+      later,
+      MediaRpcCmd::Offer(exchange("from", Some("to"), REMOTE_SDP)),
+    );
+    assert!(matches!(res, MediaRpcResult::Offer(_)));
+    tick(&mut worker, now + Duration::from_secs(6));
+    assert!(query(&mut worker, now).is_some());
+  }
+
+  #[test]
+  fn deleted_branch_restores_previous_remote() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    rpc(&mut worker, now, MediaRpcCmd::Offer(exchange("from", None, REMOTE_SDP)));
+    for (tag, addr) in [("branch-1", "192.168.1.20"), ("branch-2", "192.168.1.30")] {
+      let answer = REMOTE_SDP.replace("192.168.1.10", addr);
+      let (res, _) = rpc(
+        &mut worker,
+        now,
+        MediaRpcCmd::Answer(exchange("from", Some(tag), &answer)),
+      );
+      assert!(matches!(res, MediaRpcResult::Answer(_)));
+    }
+
+    assert_eq!(
+      rpc(&mut worker, now, end(Some("branch-2"), None)).0,
+      MediaRpcResult::End
+    );
+    let stats = query(&mut worker, now).expect("call kept");
+    let leg = stats
+      .legs
+      .iter()
+      .find(|leg| leg.tag == "branch-1")
+      .expect("previous branch");
+    assert_eq!(leg.remote_addr, "192.168.1.20:4000");
+  }
+
+  // the streams go idle on the tick reaching their deadline, the call ends on the next one
+  fn tick_twice(worker: &mut RtpEngineMediaWorker, now: Instant) -> Vec<Output> {
+    let mut outputs = tick(worker, now);
+    outputs.extend(tick(worker, now));
+    outputs
+  }
+
+  #[test]
+  fn idle_timeout() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    start_call(&mut worker, now);
+
+    assert!(ended(&tick_twice(&mut worker, now + Duration::from_secs(59))).is_empty());
+    let end_at = now + Duration::from_secs(60);
+    assert_eq!(ended(&tick_twice(&mut worker, end_at)), vec![CallEndReason::Timeout]);
+    assert!(query(&mut worker, end_at).is_none());
+
+    // the ports come back after the quarantine only
+    tick(&mut worker, end_at + Duration::from_secs(9));
+    assert_eq!(worker.store.next_port(0), None);
+    tick(&mut worker, end_at + Duration::from_secs(10));
+    assert!(worker.store.next_port(0).is_some());
+  }
+
+  #[test]
+  fn ringing_call_kept() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    let (res, _) = rpc(&mut worker, now, MediaRpcCmd::Offer(exchange("from", None, REMOTE_SDP)));
+    assert!(matches!(res, MediaRpcResult::Offer(_)));
+
+    let answered_at = now + Duration::from_secs(120);
+    assert!(ended(&tick_twice(&mut worker, answered_at)).is_empty());
+    let answer = REMOTE_SDP.replace("192.168.1.10", "192.168.1.20");
+    let (res, _) = rpc(
+      &mut worker,
+      answered_at,
+      MediaRpcCmd::Answer(exchange("from", Some("to"), &answer)),
+    );
+    assert!(matches!(res, MediaRpcResult::Answer(_)));
+
+    // the idle timeout starts with the answer
+    assert!(ended(&tick_twice(&mut worker, answered_at + Duration::from_secs(59))).is_empty());
+    assert_eq!(
+      ended(&tick_twice(&mut worker, answered_at + Duration::from_secs(60))),
+      vec![CallEndReason::Timeout]
+    );
+  }
+
+  #[test]
+  fn silent_timeout() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    let hold = format!("{}a=sendonly\r\n", REMOTE_SDP);
+    let (res, _) = rpc(&mut worker, now, MediaRpcCmd::Offer(exchange("from", None, &hold)));
+    assert!(matches!(res, MediaRpcResult::Offer(_)));
+    let answer = REMOTE_SDP.replace("192.168.1.10", "192.168.1.20");
+    let (res, _) = rpc(
+      &mut worker,
+      now,
+      MediaRpcCmd::Answer(exchange("from", Some("to"), &answer)),
+    );
+    assert!(matches!(res, MediaRpcResult::Answer(_)));
+
+    assert!(ended(&tick_twice(&mut worker, now + Duration::from_secs(60))).is_empty());
+    let end_at = now + Duration::from_secs(3600);
+    assert_eq!(
+      ended(&tick_twice(&mut worker, end_at)),
+      vec![CallEndReason::SilentTimeout]
+    );
+    assert_eq!(worker.store.next_port(0), None);
+  }
+
+  #[test]
+  fn final_timeout() {
+    let now = Instant::now();
+    let mut worker = worker(Some(Duration::from_secs(30)));
+    start_call(&mut worker, now);
+
+    assert!(ended(&tick(&mut worker, now + Duration::from_secs(29))).is_empty());
+    let end_at = now + Duration::from_secs(30);
+    assert_eq!(ended(&tick(&mut worker, end_at)), vec![CallEndReason::FinalTimeout]);
+    assert!(query(&mut worker, end_at).is_none());
+    assert_eq!(worker.store.next_port(0), None);
+  }
+
+  fn offer_origin(worker: &mut RtpEngineMediaWorker, now: Instant, sdp: &str, replace: &[Replace]) -> String {
+    let mut req = exchange("from", Some("to"), sdp);
+    req.options.replace = replace.to_vec();
+    match rpc(worker, now, MediaRpcCmd::Offer(req)).0 {
+      MediaRpcResult::Offer(sdp) => sdp
+        .lines()
+        .find(|line| line.starts_with("o="))
+        .expect("origin")
+        .to_string(),
+      res => panic!("offer failed: {:?}", res),
+    }
+  }
+
+  #[test]
+  fn stable_origin() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    let remote = REMOTE_SDP.replace("o=- 1 1", "o=alice 1 1");
+    assert_eq!(
+      offer_origin(&mut worker, now, &remote, &[Replace::Origin]),
+      "o=alice 1 1 IN IP4 10.0.0.1"
+    );
+    let answer = REMOTE_SDP.replace("192.168.1.10", "192.168.1.20");
+    let (res, _) = rpc(
+      &mut worker,
+      now,
+      MediaRpcCmd::Answer(exchange("from", Some("to"), &answer)),
+    );
+    assert!(matches!(res, MediaRpcResult::Answer(_)));
+
+    // a new origin from the offerer with the same media keeps what the callee already got
+    let remote = REMOTE_SDP.replace("o=- 1 1", "o=bob 9 9");
+    assert_eq!(
+      offer_origin(&mut worker, now, &remote, &[Replace::Origin]),
+      "o=alice 1 1 IN IP4 10.0.0.1"
+    );
+    let remote = remote.replace("RTP/AVP 0", "RTP/AVP 0 8");
+    assert_eq!(
+      offer_origin(&mut worker, now, &remote, &[Replace::Origin]),
+      "o=alice 1 2 IN IP4 10.0.0.1"
+    );
+  }
+
+  #[test]
+  fn sdp_version_follows_changes() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    start_call(&mut worker, now);
+
+    assert_eq!(
+      offer_origin(&mut worker, now, REMOTE_SDP, &[Replace::SdpVersion]),
+      "o=- 1 1 IN IP4 192.168.1.10"
+    );
+    let moved = REMOTE_SDP.replace("RTP/AVP 0", "RTP/AVP 8");
+    assert_eq!(
+      offer_origin(&mut worker, now, &moved, &[Replace::SdpVersion]),
+      "o=- 1 2 IN IP4 192.168.1.10"
+    );
+    assert_eq!(
+      offer_origin(&mut worker, now, &moved, &[Replace::SdpVersion]),
+      "o=- 1 2 IN IP4 192.168.1.10"
+    );
+  }
+
+  #[test]
+  fn released_streams_leave_the_bus() {
+    let now = Instant::now();
+    let mut worker = worker(None);
+    start_call(&mut worker, now);
+    assert_eq!(worker.tasks(), 2);
+
+    let (res, outputs) = rpc(&mut worker, now, end(None, None));
+    assert_eq!(res, MediaRpcResult::End);
+    let unsubscribed = outputs
+      .iter()
+      .filter(|out| {
+        matches!(
+          out,
+          WorkerInnerOutput::Bus(BusControl::Channel(
+            _,
+            BusChannelControl::Unsubscribe(ChannelId::Call(_, 0))
+          ))
+        )
+      })
+      .count();
+    assert_eq!(unsubscribed, 2);
+    assert_eq!(worker.tasks(), 0);
   }
 }
@@ -1,85 +1,526 @@
+use derive_more::Display;
 use sdp::{
   description::{
-    common::{Address, ConnectionInformation},
-    media::{MediaName, RangedPort},
+    common::{Address, Attribute, ConnectionInformation},
+    media::{MediaDescription, RangedPort},
     session::{Origin, TimeDescription, Timing},
   },
-  MediaDescription, SessionDescription,
+  SessionDescription,
 };
 
+//the engine does not do ice, the attributes of the remote would let the peers reach each other directly
+const ICE_ATTRIBUTES: [&str; 7] = [
+  "candidate", "end-of-candidates", "remote-candidates", "ice-ufrag", "ice-pwd", "ice-options", "ice-lite",
+];
+
+//names of the static payload types an m-line may list without rtpmap (RFC 3551 6)
+const STATIC_PAYLOAD_TYPES: [(&str, &str); 6] = [
+  ("0", "PCMU"),
+  ("3", "GSM"),
+  ("4", "G723"),
+  ("8", "PCMA"),
+  ("9", "G722"),
+  ("18", "G729"),
+];
+
+//o= username and s= name used in place of the remote ones, as RFC 4566 suggests when there is none
+pub const SDP_ANONYMOUS: &str = "-";
+
+pub struct MediaConfig {
+  pub rtp_port: isize,
+  pub rtcp_port: isize,
+  pub rtcp_mux: bool,
+}
+
 pub struct SdpConfig {
-  pub origin: Origin,
   pub addr: String,
-  pub rtp_port: isize,
+  //one per m-line, in order
+  pub media: Vec<MediaConfig>,
+  //replaces the o= line of the remote
+  pub origin: Option<Origin>,
+  //replaces the s= line of the remote
+  pub session_name: Option<String>,
+  //codec names removed from every m-line, with their rtpmap, fmtp and rtcp-fb lines
+  pub remove_codecs: Vec<String>,
 }
 
-pub fn get_sdp(sdp: &str, ip: &str, rtp: usize) -> Result<(String, String), String> {
-  match SessionDescription::try_from(sdp.to_string()).map_err(|e| e.to_string()) {
-    Ok(remote_sdp) => {
-      let remote_addr = remote_sdp.connection_information.unwrap().address.unwrap().address;
-      let remote_rtp_port = remote_sdp.media_descriptions[0].media_name.port.value;
-      let local_sdp = generate_sdp(SdpConfig {
-        origin: remote_sdp.origin,
-        addr: ip.to_string(),
-        rtp_port: rtp as isize,
-      });
-      Ok((format!("{}:{}", remote_addr, remote_rtp_port), local_sdp))
-    }
-    Err(e) => Err(e.to_string()),
+pub struct RemoteSdp {
+  pub sdp: SessionDescription,
+  pub media: Vec<RemoteMedia>,
+}
+
+pub struct RemoteMedia {
+  //"addr:port" of the rtp
+  pub rtp_addr: String,
+  //"addr:port" of the rtcp, from a=rtcp or the port after the rtp one
+  pub rtcp_addr: String,
+  pub rtcp_mux: bool,
+}
+
+#[derive(Debug, Display, Clone, PartialEq, Eq)]
+pub enum SdpError {
+  #[display(fmt = "invalid sdp: {}", _0)]
+  Parse(String),
+  #[display(fmt = "no media in sdp")]
+  NoMedia,
+  //m-line index
+  #[display(fmt = "no connection address for media {}", _0)]
+  NoConnection(usize),
+  #[display(fmt = "invalid rtcp attribute: {}", _0)]
+  InvalidRtcp(String),
+}
+
+// "addr:port", with brackets around ipv6 addresses
+pub fn join_addr(addr: &str, port: impl std::fmt::Display) -> String {
+  if addr.contains(':') {
+    format!("[{}]:{}", addr, port)
+  } else {
+    format!("{}:{}", addr, port)
   }
 }
 
-pub fn generate_sdp(cfg: SdpConfig) -> String {
-  let media_description = MediaDescription {
-    media_name: MediaName {
-      media: "audio".to_string(),
-      port: RangedPort {
-        value: cfg.rtp_port,
-        range: None,
-      },
-      protos: vec!["RTP".to_string(), "AVP".to_string()],
-      formats: vec![],
-    },
-    media_title: None,
-    connection_information: None,
-    bandwidth: vec![],
-    encryption_key: None,
-    attributes: vec![],
-  }
-  .with_codec(
-    106,
-    "opus".to_string(),
-    48000,
-    2,
-    "sprop-maxcapturerate=16000; minptime=20; useinbandfec=1".to_string(),
-  )
-  .with_codec(9, "G722".to_string(), 8000, 0, "".to_string())
-  .with_codec(0, "PCMU".to_string(), 8000, 0, "".to_string())
-  .with_codec(8, "PCMA".to_string(), 8000, 0, "".to_string())
-  .with_codec(3, "GSM".to_string(), 8000, 0, "".to_string())
-  .with_codec(98, "telephone-event".to_string(), 48000, 0, "0-16".to_string())
-  .with_codec(101, "telephone-event".to_string(), 8000, 0, "0-16".to_string())
-  .with_property_attribute("sendrecv".to_string());
-  // .with_value_attribute("rtcp".to_string(), cfg.rtcp_port.to_string())
-  // .with_property_attribute("rtcp-mux".to_string());
-  let mut sdp = SessionDescription::default().with_media(media_description);
-  sdp.session_name = cfg.origin.username.clone();
-  sdp.origin = cfg.origin;
+fn connection_address(connection: &Option<ConnectionInformation>) -> Option<&str> {
+  connection
+    .as_ref()
+    .and_then(|c| c.address.as_ref())
+    .map(|addr| addr.address.as_str())
+}
+
+pub fn get_sdp(sdp: &str) -> Result<RemoteSdp, SdpError> {
+  let remote_sdp = SessionDescription::try_from(sdp.to_string()).map_err(|e| SdpError::Parse(e.to_string()))?;
+  if remote_sdp.media_descriptions.is_empty() {
+    return Err(SdpError::NoMedia);
+  }
+  let session_addr = connection_address(&remote_sdp.connection_information);
+  let mut media = Vec::with_capacity(remote_sdp.media_descriptions.len());
+  for (index, description) in remote_sdp.media_descriptions.iter().enumerate() {
+    let remote_rtp_port = description.media_name.port.value;
+    // a media level c= line takes precedence over the session one (RFC 4566 5.7), a rejected
+    // m-line may have neither
+    let remote_addr = match connection_address(&description.connection_information).or(session_addr) {
+      Some(addr) => addr,
+      None if remote_rtp_port == 0 => "0.0.0.0",
+      None => return Err(SdpError::NoConnection(index)),
+    };
+    // a=rtcp:<port> [IN IP4|IP6 <addr>]
+    let rtcp_addr = match description.attribute("rtcp").flatten() {
+      Some(value) => {
+        let mut parts = value.split_whitespace();
+        let port = parts
+          .next()
+          .and_then(|port| port.parse::<u16>().ok())
+          .ok_or(SdpError::InvalidRtcp(value.to_string()))?;
+        let addr = parts.nth(2).unwrap_or(remote_addr);
+        join_addr(addr, port)
+      }
+      None => join_addr(remote_addr, remote_rtp_port + 1),
+    };
+    media.push(RemoteMedia {
+      rtp_addr: join_addr(remote_addr, remote_rtp_port),
+      rtcp_addr,
+      rtcp_mux: description.attribute("rtcp-mux").is_some(),
+    });
+  }
+  Ok(RemoteSdp { sdp: remote_sdp, media })
+}
+
+// true when the description puts the media on hold: a non sendrecv direction or a 0.0.0.0 connection
+pub fn is_hold_sdp(sdp: &str) -> bool {
+  let sdp = match SessionDescription::try_from(sdp.to_string()) {
+    Ok(sdp) => sdp,
+    Err(_) => return false,
+  };
+  let is_hold_direction = |attributes: &Vec<Attribute>| {
+    attributes
+      .iter()
+      .any(|attr| matches!(attr.key.as_str(), "sendonly" | "recvonly" | "inactive"))
+  };
+  let is_hold_connection =
+    |connection: &Option<ConnectionInformation>| connection_address(connection) == Some("0.0.0.0");
+  if is_hold_direction(&sdp.attributes) || is_hold_connection(&sdp.connection_information) {
+    return true;
+  }
+  sdp
+    .media_descriptions
+    .iter()
+    .any(|media| is_hold_direction(&media.attributes) || is_hold_connection(&media.connection_information))
+}
+
+pub fn address_type(addr: &str) -> &'static str {
+  if addr.contains(':') {
+    "IP6"
+  } else {
+    "IP4"
+  }
+}
+
+pub fn get_origin(sdp: &str) -> Option<Origin> {
+  SessionDescription::try_from(sdp.to_string()).ok().map(|sdp| sdp.origin)
+}
+
+// true when two descriptions only differ by their o= line, a new version is not needed
+pub fn same_session(a: &str, b: &str) -> bool {
+  let lines = |sdp: &'_ str| -> Vec<String> {
+    sdp
+      .lines()
+      .filter(|line| !line.starts_with("o="))
+      .map(|line| line.to_string())
+      .collect()
+  };
+  lines(a) == lines(b)
+}
+
+// The local description is the remote one with the engine in place of the endpoint: m-lines keep
+// their order, formats, rtpmap, fmtp and mid, only the connection address, the ports and the rtcp
+// lines change, and the ice attributes are removed. An m-line without config, or with port 0 in its
+// config, is rejected with port 0. The codecs to remove are left in place when they are all the
+// m-line has, an m-line without format is invalid.
+pub fn generate_sdp(remote: &SessionDescription, cfg: SdpConfig) -> String {
+  let mut sdp = remote.clone();
+  sdp
+    .attributes
+    .retain(|attr| !ICE_ATTRIBUTES.contains(&attr.key.as_str()));
+  for (index, media) in sdp.media_descriptions.iter_mut().enumerate() {
+    media.connection_information = None;
+    media
+      .attributes
+      .retain(|attr| !matches!(attr.key.as_str(), "rtcp" | "rtcp-mux") && !ICE_ATTRIBUTES.contains(&attr.key.as_str()));
+    remove_codecs(media, &cfg.remove_codecs);
+    let config = match cfg.media.get(index) {
+      Some(config) if config.rtp_port != 0 => config,
+      _ => {
+        media.media_name.port = RangedPort { value: 0, range: None };
+        continue;
+      }
+    };
+    media.media_name.port = RangedPort {
+      value: config.rtp_port,
+      range: None,
+    };
+    // with rtcp-mux the rtcp goes to the rtp port (RFC 5761 5.1.3)
+    let rtcp_port = match config.rtcp_mux {
+      true => config.rtp_port,
+      false => config.rtcp_port,
+    };
+    media
+      .attributes
+      .push(Attribute::new("rtcp".to_string(), Some(rtcp_port.to_string())));
+    if config.rtcp_mux {
+      media.attributes.push(Attribute::new("rtcp-mux".to_string(), None));
+    }
+  }
+
+  if let Some(origin) = cfg.origin {
+    sdp.origin = origin;
+  }
+  if let Some(session_name) = cfg.session_name {
+    sdp.session_name = session_name;
+  }
+  let address_type = address_type(&cfg.addr);
   sdp.connection_information = Some(ConnectionInformation {
     network_type: "IN".to_string(),
-    address_type: "IP4".to_string(),
+    address_type: address_type.to_string(),
     address: Some(Address {
       address: cfg.addr,
       ttl: None,
       range: None,
     }),
   });
-  sdp.time_descriptions = vec![TimeDescription {
-    timing: Timing {
-      start_time: 0,
-      stop_time: 0,
-    },
-    repeat_times: vec![],
-  }];
+  if sdp.time_descriptions.is_empty() {
+    sdp.time_descriptions = vec![TimeDescription {
+      timing: Timing {
+        start_time: 0,
+        stop_time: 0,
+      },
+      repeat_times: vec![],
+    }];
+  }
   sdp.marshal()
 }
+
+// the codec name of a payload type, from its rtpmap or the static payload types
+fn codec_name<'a>(media: &'a MediaDescription, format: &'a str) -> Option<&'a str> {
+  media
+    .attributes
+    .iter()
+    .filter(|attr| attr.key == "rtpmap")
+    .filter_map(|attr| attr.value.as_deref()?.split_once(' '))
+    .find(|(payload_type, _)| *payload_type == format)
+    .and_then(|(_, encoding)| encoding.split('/').next())
+    .or_else(|| {
+      STATIC_PAYLOAD_TYPES
+        .iter()
+        .find(|(payload_type, _)| *payload_type == format)
+        .map(|(_, name)| *name)
+    })
+}
+
+fn remove_codecs(media: &mut MediaDescription, codecs: &[String]) {
+  if codecs.is_empty() {
+    return;
+  }
+  let removed: Vec<String> = media
+    .media_name
+    .formats
+    .iter()
+    .filter(|format| {
+      codec_name(media, format).is_some_and(|name| codecs.iter().any(|codec| codec.eq_ignore_ascii_case(name)))
+    })
+    .cloned()
+    .collect();
+  if removed.is_empty() || removed.len() == media.media_name.formats.len() {
+    return;
+  }
+  media.media_name.formats.retain(|format| !removed.contains(format));
+  media.attributes.retain(|attr| {
+    let payload_type = match attr.key.as_str() {
+      "rtpmap" | "fmtp" | "rtcp-fb" => attr.value.as_deref().and_then(|value| value.split(' ').next()),
+      _ => None,
+    };
+    !payload_type.is_some_and(|payload_type| removed.iter().any(|format| format == payload_type))
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use sdp::SessionDescription;
+
+  use super::{generate_sdp, get_origin, get_sdp, same_session, MediaConfig, SdpConfig, SdpError};
+
+  const REMOTE_SDP: &str = "v=0\r\no=- 1 1 IN IP4 192.168.1.10\r\ns=-\r\nc=IN IP4 192.168.1.10\r\nt=0 0\r\nm=audio 4000 RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\n";
+
+  #[test]
+  fn advertise_rtcp_port() {
+    let remote = get_sdp(REMOTE_SDP).unwrap();
+    assert_eq!(remote.media[0].rtp_addr, "192.168.1.10:4000");
+    let local = generate_sdp(
+      &remote.sdp,
+      SdpConfig {
+        addr: "10.0.0.1".to_string(),
+        media: vec![MediaConfig {
+          rtp_port: 10000,
+          rtcp_port: 10001,
+          rtcp_mux: false,
+        }],
+        origin: None,
+        session_name: None,
+        remove_codecs: vec![],
+      },
+    );
+    assert!(local.contains("m=audio 10000 RTP/AVP 0\r\n"));
+    assert!(local.contains("c=IN IP4 10.0.0.1\r\n"));
+    assert!(local.contains("a=rtcp:10001\r\n"));
+    assert!(!local.contains("a=rtcp-mux"));
+  }
+
+  #[test]
+  fn keep_remote_codecs() {
+    let sdp = "v=0\r\no=- 1 1 IN IP4 192.168.1.10\r\ns=-\r\nt=0 0\r\nm=audio 4000 RTP/AVP 96 8 101\r\nc=IN IP4 192.168.1.10\r\na=rtpmap:96 opus/48000/2\r\na=fmtp:96 useinbandfec=1\r\na=rtpmap:8 PCMA/8000\r\na=rtpmap:101 telephone-event/8000\r\na=rtcp:4001\r\na=sendonly\r\n";
+    let remote = SessionDescription::try_from(sdp.to_string()).unwrap();
+    let local = generate_sdp(
+      &remote,
+      SdpConfig {
+        addr: "10.0.0.1".to_string(),
+        media: vec![MediaConfig {
+          rtp_port: 10000,
+          rtcp_port: 10001,
+          rtcp_mux: true,
+        }],
+        origin: None,
+        session_name: None,
+        remove_codecs: vec![],
+      },
+    );
+    assert!(local.contains("m=audio 10000 RTP/AVP 96 8 101\r\n"));
+    assert!(local.contains("a=rtpmap:96 opus/48000/2\r\na=fmtp:96 useinbandfec=1\r\na=rtpmap:8 PCMA/8000\r\n"));
+    assert!(local.contains("a=sendonly\r\n"));
+    assert!(local.contains("a=rtcp:10000\r\na=rtcp-mux\r\n"));
+    assert!(!local.contains("c=IN IP4 192.168.1.10"));
+    assert!(!local.contains("PCMU"));
+  }
+
+  #[test]
+  fn strip_ice() {
+    let sdp = format!(
+      "{}a=ice-ufrag:F7gI\r\na=ice-pwd:x9cml/YzichV2+XlhiMu8g\r\na=candidate:1 1 UDP 2130706431 192.168.1.10 4000 typ host\r\na=candidate:2 1 UDP 1694498815 203.0.113.7 4000 typ srflx raddr 192.168.1.10 rport 4000\r\na=end-of-candidates\r\na=sendrecv\r\n",
+      REMOTE_SDP
+    );
+    let remote = get_sdp(&sdp).unwrap();
+    let local = generate_sdp(
+      &remote.sdp,
+      SdpConfig {
+        addr: "10.0.0.1".to_string(),
+        media: vec![MediaConfig {
+          rtp_port: 10000,
+          rtcp_port: 10001,
+          rtcp_mux: false,
+        }],
+        origin: None,
+        session_name: None,
+        remove_codecs: vec![],
+      },
+    );
+    assert!(!local.contains("a=candidate"));
+    assert!(!local.contains("a=end-of-candidates"));
+    assert!(!local.contains("a=ice-"));
+    assert!(!local.contains("203.0.113.7"));
+    assert!(local.contains("a=sendrecv\r\n"));
+  }
+
+  #[test]
+  fn remote_rtcp() {
+    let remote = get_sdp(REMOTE_SDP).unwrap();
+    assert_eq!(remote.media[0].rtcp_addr, "192.168.1.10:4001");
+    assert!(!remote.media[0].rtcp_mux);
+
+    let sdp = format!("{}a=rtcp:4005 IN IP4 192.168.1.11\r\na=rtcp-mux\r\n", REMOTE_SDP);
+    let remote = get_sdp(&sdp).unwrap();
+    assert_eq!(remote.media[0].rtcp_addr, "192.168.1.11:4005");
+    assert!(remote.media[0].rtcp_mux);
+  }
+
+  #[test]
+  fn media_level_connection() {
+    let sdp = "v=0\r\no=- 1 1 IN IP4 192.168.1.10\r\ns=-\r\nt=0 0\r\nm=audio 4000 RTP/AVP 0\r\nc=IN IP4 192.168.1.20\r\nm=video 0 RTP/AVP 96\r\n";
+    let remote = get_sdp(sdp).unwrap();
+    assert_eq!(remote.media[0].rtp_addr, "192.168.1.20:4000");
+    assert_eq!(remote.media[1].rtp_addr, "0.0.0.0:0");
+
+    let sdp = format!("{}a=mid:0\r\nc=IN IP4 192.168.1.30\r\n", REMOTE_SDP);
+    assert_eq!(get_sdp(&sdp).unwrap().media[0].rtp_addr, "192.168.1.30:4000");
+  }
+
+  #[test]
+  fn ipv6_media() {
+    let sdp = "v=0\r\no=- 1 1 IN IP6 2001:db8::1\r\ns=-\r\nc=IN IP6 2001:db8::1\r\nt=0 0\r\nm=audio 4000 RTP/AVP 0\r\n";
+    let remote = get_sdp(sdp).unwrap();
+    assert_eq!(remote.media[0].rtp_addr, "[2001:db8::1]:4000");
+    assert_eq!(remote.media[0].rtcp_addr, "[2001:db8::1]:4001");
+
+    let local = generate_sdp(
+      &remote.sdp,
+      SdpConfig {
+        addr: "10.0.0.1".to_string(),
+        media: vec![MediaConfig {
+          rtp_port: 10000,
+          rtcp_port: 10001,
+          rtcp_mux: false,
+        }],
+        origin: None,
+        session_name: None,
+        remove_codecs: vec![],
+      },
+    );
+    assert!(local.contains("c=IN IP4 10.0.0.1\r\n"));
+    let local = generate_sdp(
+      &get_sdp(REMOTE_SDP).unwrap().sdp,
+      SdpConfig {
+        addr: "2001:db8::2".to_string(),
+        media: vec![],
+        origin: None,
+        session_name: None,
+        remove_codecs: vec![],
+      },
+    );
+    assert!(local.contains("c=IN IP6 2001:db8::2\r\n"));
+  }
+
+  #[test]
+  fn malformed_sdp() {
+    let sdp = "v=0\r\no=- 1 1 IN IP4 192.168.1.10\r\ns=-\r\nt=0 0\r\nm=audio 4000 RTP/AVP 0\r\n";
+    assert_eq!(get_sdp(sdp).err(), Some(SdpError::NoConnection(0)));
+    let sdp = "v=0\r\no=- 1 1 IN IP4 192.168.1.10\r\ns=-\r\nc=IN IP4 192.168.1.10\r\nt=0 0\r\n";
+    assert_eq!(get_sdp(sdp).err(), Some(SdpError::NoMedia));
+    let sdp = format!("{}a=rtcp:abc\r\n", REMOTE_SDP);
+    assert_eq!(get_sdp(&sdp).err(), Some(SdpError::InvalidRtcp("abc".to_string())));
+    assert!(matches!(get_sdp("garbage"), Err(SdpError::Parse(_))));
+  }
+
+  #[test]
+  fn multiple_media() {
+    let sdp = format!(
+      "{}a=mid:0\r\nm=video 4002 RTP/AVP 96\r\na=rtpmap:96 VP8/90000\r\na=mid:1\r\nm=image 4004 udptl t38\r\n",
+      REMOTE_SDP
+    );
+    let remote = get_sdp(&sdp).unwrap();
+    let addrs: Vec<&str> = remote.media.iter().map(|media| media.rtp_addr.as_str()).collect();
+    assert_eq!(
+      addrs,
+      vec!["192.168.1.10:4000", "192.168.1.10:4002", "192.168.1.10:4004"]
+    );
+
+    let media = |port| MediaConfig {
+      rtp_port: port,
+      rtcp_port: port + 1,
+      rtcp_mux: false,
+    };
+    let local = generate_sdp(
+      &remote.sdp,
+      SdpConfig {
+        addr: "10.0.0.1".to_string(),
+        media: vec![media(10000), media(10002)],
+        origin: None,
+        session_name: None,
+        remove_codecs: vec![],
+      },
+    );
+    assert!(local.contains("m=audio 10000 RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\na=mid:0\r\na=rtcp:10001\r\n"));
+    assert!(local.contains("m=video 10002 RTP/AVP 96\r\na=rtpmap:96 VP8/90000\r\na=mid:1\r\na=rtcp:10003\r\n"));
+    assert!(local.contains("m=image 0 udptl t38\r\n"));
+  }
+
+  #[test]
+  fn replace_origin() {
+    let remote = get_sdp(REMOTE_SDP).unwrap();
+    let config = |origin| SdpConfig {
+      addr: "10.0.0.1".to_string(),
+      media: vec![MediaConfig {
+        rtp_port: 10000,
+        rtcp_port: 10001,
+        rtcp_mux: false,
+      }],
+      origin,
+      session_name: None,
+      remove_codecs: vec![],
+    };
+    let local = generate_sdp(&remote.sdp, config(None));
+    assert!(local.contains("o=- 1 1 IN IP4 192.168.1.10\r\ns=-\r\n"));
+
+    let mut origin = get_origin(&local).unwrap();
+    origin.session_id = 42;
+    origin.session_version = 2;
+    origin.unicast_address = "10.0.0.1".to_string();
+    let replaced = generate_sdp(&remote.sdp, config(Some(origin)));
+    assert!(replaced.contains("o=- 42 2 IN IP4 10.0.0.1\r\n"));
+    assert!(same_session(&local, &replaced));
+
+    let mut moved = config(None);
+    moved.media[0].rtp_port = 10002;
+    assert!(!same_session(&local, &generate_sdp(&remote.sdp, moved)));
+  }
+
+  #[test]
+  fn remove_codecs() {
+    let sdp = "v=0\r\no=- 1 1 IN IP4 192.168.1.10\r\ns=-\r\nc=IN IP4 192.168.1.10\r\nt=0 0\r\nm=audio 4000 RTP/AVP 96 8 0 101\r\na=rtpmap:96 opus/48000/2\r\na=fmtp:96 useinbandfec=1\r\na=rtcp-fb:96 nack\r\na=rtpmap:101 telephone-event/8000\r\na=ptime:20\r\nm=video 4002 RTP/AVP 97\r\na=rtpmap:97 VP8/90000\r\n";
+    let remote = get_sdp(sdp).unwrap();
+    let media = |port| MediaConfig {
+      rtp_port: port,
+      rtcp_port: port + 1,
+      rtcp_mux: false,
+    };
+    let local = generate_sdp(
+      &remote.sdp,
+      SdpConfig {
+        addr: "10.0.0.1".to_string(),
+        media: vec![media(10000), media(10002)],
+        origin: None,
+        session_name: None,
+        remove_codecs: vec!["OPUS".to_string(), "PCMU".to_string(), "VP8".to_string()],
+      },
+    );
+    assert!(local
+      .contains("m=audio 10000 RTP/AVP 8 101\r\na=rtpmap:101 telephone-event/8000\r\na=ptime:20\r\na=rtcp:10001\r\n"));
+    // the only codec of the m-line stays
+    assert!(local.contains("m=video 10002 RTP/AVP 97\r\na=rtpmap:97 VP8/90000\r\n"));
+  }
+}
@@ -0,0 +1,142 @@
+use std::{
+  collections::{HashMap, VecDeque},
+  net::SocketAddr,
+  time::{Duration, Instant},
+};
+
+//how long a reply is kept for the retransmissions of its request
+pub const RESPONSE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+enum CacheEntry {
+  //the request is being processed, its retransmissions are dropped
+  Pending(Instant),
+  //encoded reply
+  Done(Instant, String),
+}
+
+pub enum CacheLookup {
+  //first time the request is seen, it must be processed
+  New,
+  //retransmission of a request still being processed
+  Pending,
+  //retransmission of an answered request, the reply is sent again
+  Done(String),
+}
+
+// Replies by (source address, cookie), so a retransmitted request is not run twice and two clients
+// using the same cookie don't get each other's replies.
+pub struct ResponseCache {
+  ttl: Duration,
+  entries: HashMap<(SocketAddr, String), CacheEntry>,
+  //keys in the order they expire, a stored reply pushes its key again with the new expiry
+  expiries: VecDeque<(Instant, (SocketAddr, String))>,
+}
+
+impl ResponseCache {
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      entries: HashMap::new(),
+      expiries: VecDeque::new(),
+    }
+  }
+
+  // a request seen for the first time is marked as pending
+  pub fn lookup(&mut self, now: Instant, addr: SocketAddr, cookie: &str) -> CacheLookup {
+    self.purge(now);
+    match self.entries.get(&(addr, cookie.to_string())) {
+      Some(CacheEntry::Pending(_)) => CacheLookup::Pending,
+      Some(CacheEntry::Done(_, reply)) => CacheLookup::Done(reply.clone()),
+      None => {
+        let key = (addr, cookie.to_string());
+        self.expiries.push_back((now + self.ttl, key.clone()));
+        self.entries.insert(key, CacheEntry::Pending(now + self.ttl));
+        CacheLookup::New
+      }
+    }
+  }
+
+  pub fn store(&mut self, now: Instant, addr: SocketAddr, cookie: &str, reply: &str) {
+    let key = (addr, cookie.to_string());
+    self.expiries.push_back((now + self.ttl, key.clone()));
+    self
+      .entries
+      .insert(key, CacheEntry::Done(now + self.ttl, reply.to_string()));
+  }
+
+  // only the expired front of the queue is visited, an entry renewed since it was queued is kept
+  fn purge(&mut self, now: Instant) {
+    while let Some((at, _)) = self.expiries.front() {
+      if *at > now {
+        break;
+      }
+      let (_, key) = self.expiries.pop_front().expect("front checked");
+      let expired = match self.entries.get(&key) {
+        Some(CacheEntry::Pending(expire_at)) | Some(CacheEntry::Done(expire_at, _)) => *expire_at <= now,
+        None => false,
+      };
+      if expired {
+        self.entries.remove(&key);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::{Duration, Instant};
+
+  use super::{CacheLookup, ResponseCache};
+
+  #[test]
+  fn retransmission_gets_cached_reply() {
+    let now = Instant::now();
+    let addr = "127.0.0.1:5060".parse().unwrap();
+    let mut cache = ResponseCache::new(Duration::from_secs(30));
+    assert!(matches!(cache.lookup(now, addr, "cookie"), CacheLookup::New));
+    assert!(matches!(cache.lookup(now, addr, "cookie"), CacheLookup::Pending));
+    cache.store(now, addr, "cookie", "cookie d6:result4:ponge");
+    match cache.lookup(now, addr, "cookie") {
+      CacheLookup::Done(reply) => assert_eq!(reply, "cookie d6:result4:ponge"),
+      _ => panic!("reply not cached"),
+    }
+  }
+
+  #[test]
+  fn cookie_scoped_by_source() {
+    let now = Instant::now();
+    let mut cache = ResponseCache::new(Duration::from_secs(30));
+    assert!(matches!(
+      cache.lookup(now, "127.0.0.1:5060".parse().unwrap(), "cookie"),
+      CacheLookup::New
+    ));
+    assert!(matches!(
+      cache.lookup(now, "127.0.0.2:5060".parse().unwrap(), "cookie"),
+      CacheLookup::New
+    ));
+  }
+
+  #[test]
+  fn expired_reply() {
+    let now = Instant::now();
+    let addr = "127.0.0.1:5060".parse().unwrap();
+    let mut cache = ResponseCache::new(Duration::from_secs(30));
+    cache.lookup(now, addr, "cookie");
+    cache.store(now, addr, "cookie", "reply");
+    let later = now + Duration::from_secs(31);
+    assert!(matches!(cache.lookup(later, addr, "cookie"), CacheLookup::New));
+  }
+
+  #[test]
+  fn stored_reply_renews_expiry() {
+    let now = Instant::now();
+    let addr = "127.0.0.1:5060".parse().unwrap();
+    let mut cache = ResponseCache::new(Duration::from_secs(30));
+    cache.lookup(now, addr, "cookie");
+    cache.store(now + Duration::from_secs(20), addr, "cookie", "reply");
+    let later = now + Duration::from_secs(31);
+    assert!(matches!(cache.lookup(later, addr, "cookie"), CacheLookup::Done(_)));
+    assert!(matches!(cache.lookup(later, addr, "other"), CacheLookup::New));
+    assert_eq!(cache.expiries.len(), 2);
+  }
+}
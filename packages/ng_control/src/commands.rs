@@ -1,3 +1,6 @@
+use std::collections::BTreeMap;
+
+use log::debug;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -13,8 +16,10 @@ pub enum NgCommand {
     call_id: String,
     #[serde(rename = "from-tag")]
     from_tag: String,
-    #[serde(rename = "ICE")]
-    ice: Option<String>,
+    #[serde(rename = "to-tag")]
+    to_tag: Option<String>,
+    #[serde(flatten)]
+    options: NgSdpOptions,
   },
 
   #[serde(rename = "answer")]
@@ -26,8 +31,8 @@ pub enum NgCommand {
     from_tag: String,
     #[serde(rename = "to-tag")]
     to_tag: String,
-    #[serde(rename = "ICE")]
-    ice: Option<String>,
+    #[serde(flatten)]
+    options: NgSdpOptions,
   },
 
   #[serde(rename = "delete")]
@@ -38,19 +43,77 @@ pub enum NgCommand {
     from_tag: String,
     #[serde(rename = "to-tag")]
     to_tag: Option<String>,
+    #[serde(rename = "delete-delay")]
+    delete_delay: Option<u64>,
+  },
+
+  #[serde(rename = "query")]
+  Query {
+    #[serde(rename = "call-id")]
+    call_id: String,
+    #[serde(rename = "from-tag")]
+    from_tag: Option<String>,
+    #[serde(rename = "to-tag")]
+    to_tag: Option<String>,
   },
+
+  #[serde(rename = "list")]
+  List { limit: Option<u64> },
+}
+
+//options of the offer and answer commands, the values are checked when mapped to the media options
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct NgSdpOptions {
+  #[serde(rename = "ICE", skip_serializing_if = "Option::is_none")]
+  pub ice: Option<String>,
+  #[serde(rename = "rtcp-mux", skip_serializing_if = "Option::is_none")]
+  pub rtcp_mux: Option<Vec<String>>,
+  #[serde(rename = "address family", skip_serializing_if = "Option::is_none")]
+  pub address_family: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub direction: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub interface: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub flags: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub replace: Option<Vec<String>>,
+  #[serde(rename = "transport-protocol", skip_serializing_if = "Option::is_none")]
+  pub transport_protocol: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub codec: Option<NgCodecOptions>,
+  #[serde(rename = "SDES", skip_serializing_if = "Option::is_none")]
+  pub sdes: Option<Vec<String>>,
+  #[serde(rename = "DTLS", skip_serializing_if = "Option::is_none")]
+  pub dtls: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub label: Option<String>,
+  #[serde(rename = "via-branch", skip_serializing_if = "Option::is_none")]
+  pub via_branch: Option<String>,
+  //address family and address, like ["IP4", "192.0.2.1"]
+  #[serde(rename = "received-from", skip_serializing_if = "Option::is_none")]
+  pub received_from: Option<Vec<String>>,
+  #[serde(rename = "media-address", skip_serializing_if = "Option::is_none")]
+  pub media_address: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct NgCodecOptions {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub strip: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub offer: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub transcode: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mask: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub accept: Option<Vec<String>>,
 }
 
 impl NgCommand {
-  pub fn from_str(msg: &str) -> Option<NgCommand> {
-    let decoded: Result<NgCommand, _> = serde_bencode::de::from_str(msg); // Adjusted for clarity
-    match decoded {
-      Ok(decoded) => Some(decoded),
-      Err(e) => {
-        println!("Error: {:?}", e);
-        None
-      }
-    }
+  pub fn from_str(msg: &str) -> Result<NgCommand, String> {
+    serde_bencode::de::from_str(msg).map_err(|e| format!("Failed to decode command: {}", e))
   }
 
   pub fn to_str(&self) -> String {
@@ -63,35 +126,112 @@ impl NgCommand {
 pub enum NgCmdResult {
   Pong {
     result: String,
-    #[serde(rename = "error-reason")]
+    #[serde(rename = "error-reason", skip_serializing_if = "Option::is_none")]
     error_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
   },
   Offer {
     result: String,
-    #[serde(rename = "error-reason")]
+    #[serde(rename = "error-reason", skip_serializing_if = "Option::is_none")]
     error_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     sdp: Option<String>,
   },
   Answer {
     result: String,
-    #[serde(rename = "error-reason")]
+    #[serde(rename = "error-reason", skip_serializing_if = "Option::is_none")]
     error_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     sdp: Option<String>,
   },
   Delete {
     result: String,
-    #[serde(rename = "error-reason")]
+    #[serde(rename = "error-reason", skip_serializing_if = "Option::is_none")]
     error_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+  },
+  Query {
+    result: String,
+    #[serde(rename = "error-reason", skip_serializing_if = "Option::is_none")]
+    error_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<BTreeMap<String, NgLegStats>>,
+  },
+  List {
+    result: String,
+    #[serde(rename = "error-reason", skip_serializing_if = "Option::is_none")]
+    error_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    calls: Option<Vec<String>>,
+  },
+  Error {
+    result: String,
+    #[serde(rename = "error-reason")]
+    error_reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
   },
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct NgTrafficStats {
+  pub packets: u64,
+  pub bytes: u64,
+  pub errors: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct NgLegStats {
+  pub tag: String,
+  pub created: u64,
+  #[serde(rename = "local-address")]
+  pub local_address: String,
+  #[serde(rename = "remote-address")]
+  pub remote_address: String,
+  pub ingress: NgTrafficStats,
+  pub egress: NgTrafficStats,
+}
+
 impl NgCmdResult {
+  pub fn error(reason: &str) -> NgCmdResult {
+    NgCmdResult::Error {
+      result: "error".to_string(),
+      error_reason: reason.to_string(),
+      warning: None,
+    }
+  }
+
+  // warnings are reported next to the result, they don't make the command fail
+  pub fn set_warning(&mut self, value: String) {
+    match self {
+      NgCmdResult::Pong { warning, .. }
+      | NgCmdResult::Offer { warning, .. }
+      | NgCmdResult::Answer { warning, .. }
+      | NgCmdResult::Delete { warning, .. }
+      | NgCmdResult::Query { warning, .. }
+      | NgCmdResult::List { warning, .. }
+      | NgCmdResult::Error { warning, .. } => *warning = Some(value),
+    }
+  }
+
   pub fn from_str(msg: &str) -> Option<NgCmdResult> {
     let decoded: Result<NgCmdResult, _> = serde_bencode::de::from_str(msg); // Adjusted for clarity
     match decoded {
       Ok(decoded) => Some(decoded),
       Err(e) => {
-        println!("Error: {:?}", e);
+        debug!("invalid ng result {}: {:?}", msg, e);
         None
       }
     }
@@ -108,21 +248,39 @@ pub struct NgRequest {
   pub command: NgCommand,
 }
 
-impl NgRequest {
-  pub fn from_str(packet: &str) -> Option<NgRequest> {
-    let idx = packet.find(" ");
-    match idx {
-      Some(idx) => {
-        let id = packet[..idx].to_string();
-        let body = &packet[idx + 1..];
-        let command = NgCommand::from_str(&body).unwrap();
-        Some(NgRequest { id, command })
-      }
-      None => None,
+//a packet that could not be decoded, answered with an error under its cookie when there is one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NgDecodeError {
+  pub id: String,
+  pub reason: String,
+}
+
+impl NgDecodeError {
+  pub fn response(&self) -> NgResponse {
+    NgResponse {
+      id: self.id.clone(),
+      result: NgCmdResult::error(&self.reason),
     }
   }
 }
 
+impl NgRequest {
+  pub fn from_str(packet: &str) -> Result<NgRequest, NgDecodeError> {
+    let (id, body) = packet.split_once(' ').ok_or(NgDecodeError {
+      id: String::new(),
+      reason: "Missing cookie".to_string(),
+    })?;
+    let command = NgCommand::from_str(body).map_err(|reason| NgDecodeError {
+      id: id.to_string(),
+      reason,
+    })?;
+    Ok(NgRequest {
+      id: id.to_string(),
+      command,
+    })
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct NgResponse {
   pub id: String,
@@ -138,9 +296,11 @@ impl NgResponse {
 
 #[cfg(test)]
 mod test {
-  use crate::commands::NgCmdResult;
+  use std::collections::BTreeMap;
+
+  use crate::commands::{NgCmdResult, NgLegStats, NgTrafficStats};
 
-  use super::NgCommand;
+  use super::{NgCodecOptions, NgCommand, NgRequest, NgSdpOptions};
 
   #[test]
   fn ping_command() {
@@ -155,7 +315,8 @@ mod test {
     assert_eq!(
       NgCmdResult::Pong {
         result: "pong".to_string(),
-        error_reason: None
+        error_reason: None,
+        warning: None
       },
       NgCmdResult::from_str("d6:result4:ponge").unwrap()
     );
@@ -163,7 +324,8 @@ mod test {
     assert_eq!(
       NgCmdResult::Pong {
         result: "pong".to_string(),
-        error_reason: None
+        error_reason: None,
+        warning: None
       }
       .to_str(),
       "d6:result4:ponge".to_string()
@@ -177,9 +339,206 @@ mod test {
       sdp: "v=0".to_string(),
       call_id: "bvmWdxbe4hkHHHvCl_d-nQ..".to_string(),
       from_tag: "460d801e".to_string(),
-      ice: None,
+      to_tag: None,
+      options: NgSdpOptions::default(),
     };
     let expect: NgCommand = NgCommand::from_str(input).unwrap();
     assert_eq!(expect, actual);
   }
+
+  #[test]
+  fn reoffer_command() {
+    let input =
+      "d7:call-id24:bvmWdxbe4hkHHHvCl_d-nQ..7:command5:offer8:from-tag8:460d801e3:sdp3:v=06:to-tag8:2f8a6c01e";
+    let actual = NgCommand::Offer {
+      sdp: "v=0".to_string(),
+      call_id: "bvmWdxbe4hkHHHvCl_d-nQ..".to_string(),
+      from_tag: "460d801e".to_string(),
+      to_tag: Some("2f8a6c01".to_string()),
+      options: NgSdpOptions::default(),
+    };
+    let expect: NgCommand = NgCommand::from_str(input).unwrap();
+    assert_eq!(expect, actual);
+  }
+
+  #[test]
+  fn offer_rtcp_mux_command() {
+    let input = "d7:call-id6:call-17:command5:offer8:from-tag8:460d801e8:rtcp-muxl5:demux6:accepte3:sdp3:v=0e";
+    let actual = NgCommand::Offer {
+      sdp: "v=0".to_string(),
+      call_id: "call-1".to_string(),
+      from_tag: "460d801e".to_string(),
+      to_tag: None,
+      options: NgSdpOptions {
+        rtcp_mux: Some(vec!["demux".to_string(), "accept".to_string()]),
+        ..Default::default()
+      },
+    };
+    assert_eq!(NgCommand::from_str(input).unwrap(), actual);
+  }
+
+  #[test]
+  fn offer_address_family_command() {
+    let input = "d14:address family3:IP67:call-id6:call-17:command5:offer8:from-tag8:460d801e3:sdp3:v=0e";
+    let actual = NgCommand::Offer {
+      sdp: "v=0".to_string(),
+      call_id: "call-1".to_string(),
+      from_tag: "460d801e".to_string(),
+      to_tag: None,
+      options: NgSdpOptions {
+        address_family: Some("IP6".to_string()),
+        ..Default::default()
+      },
+    };
+    assert_eq!(NgCommand::from_str(input).unwrap(), actual);
+  }
+
+  #[test]
+  fn offer_direction_command() {
+    let input = "d7:call-id6:call-17:command5:offer9:directionl8:internal8:externale8:from-tag8:460d801e3:sdp3:v=0e";
+    let actual = NgCommand::Offer {
+      sdp: "v=0".to_string(),
+      call_id: "call-1".to_string(),
+      from_tag: "460d801e".to_string(),
+      to_tag: None,
+      options: NgSdpOptions {
+        direction: Some(vec!["internal".to_string(), "external".to_string()]),
+        ..Default::default()
+      },
+    };
+    assert_eq!(NgCommand::from_str(input).unwrap(), actual);
+  }
+
+  #[test]
+  fn offer_options_command() {
+    let input = concat!(
+      "d3:ICE6:remove4:DTLS7:passive4:SDESl3:offe7:call-id6:call-1",
+      "5:codecd5:stripl4:PCMAe9:transcodel4:opusee7:command5:offer",
+      "5:flagsl13:trust-addresse8:from-tag8:460d801e5:label6:caller",
+      "13:media-address9:192.0.2.113:received-froml3:IP49:192.0.2.2e",
+      "7:replacel6:origin18:session-connectione3:sdp3:v=0",
+      "18:transport-protocol9:RTP/SAVPF10:via-branch6:z9hG4be"
+    );
+    let actual = NgCommand::Offer {
+      sdp: "v=0".to_string(),
+      call_id: "call-1".to_string(),
+      from_tag: "460d801e".to_string(),
+      to_tag: None,
+      options: NgSdpOptions {
+        ice: Some("remove".to_string()),
+        flags: Some(vec!["trust-address".to_string()]),
+        replace: Some(vec!["origin".to_string(), "session-connection".to_string()]),
+        transport_protocol: Some("RTP/SAVPF".to_string()),
+        codec: Some(NgCodecOptions {
+          strip: Some(vec!["PCMA".to_string()]),
+          transcode: Some(vec!["opus".to_string()]),
+          ..Default::default()
+        }),
+        sdes: Some(vec!["off".to_string()]),
+        dtls: Some("passive".to_string()),
+        label: Some("caller".to_string()),
+        via_branch: Some("z9hG4b".to_string()),
+        received_from: Some(vec!["IP4".to_string(), "192.0.2.2".to_string()]),
+        media_address: Some("192.0.2.1".to_string()),
+        ..Default::default()
+      },
+    };
+    assert_eq!(NgCommand::from_str(input).unwrap(), actual);
+    assert_eq!(NgCommand::from_str(&actual.to_str()).unwrap(), actual);
+  }
+
+  #[test]
+  fn delete_command() {
+    let input = "d7:call-id6:call-17:command6:delete12:delete-delayi30e8:from-tag8:460d801e6:to-tag8:2f8a6c01e";
+    let actual = NgCommand::Delete {
+      call_id: "call-1".to_string(),
+      from_tag: "460d801e".to_string(),
+      to_tag: Some("2f8a6c01".to_string()),
+      delete_delay: Some(30),
+    };
+    assert_eq!(NgCommand::from_str(input).unwrap(), actual);
+  }
+
+  #[test]
+  fn query_command() {
+    let input = "d7:call-id24:bvmWdxbe4hkHHHvCl_d-nQ..7:command5:query8:from-tag8:460d801ee";
+    let actual = NgCommand::Query {
+      call_id: "bvmWdxbe4hkHHHvCl_d-nQ..".to_string(),
+      from_tag: Some("460d801e".to_string()),
+      to_tag: None,
+    };
+    let expect: NgCommand = NgCommand::from_str(input).unwrap();
+    assert_eq!(expect, actual);
+  }
+
+  #[test]
+  fn list_command() {
+    assert_eq!(
+      NgCommand::from_str("d7:command4:list5:limiti10ee").unwrap(),
+      NgCommand::List { limit: Some(10) }
+    );
+    assert_eq!(
+      NgCommand::from_str("d7:command4:liste").unwrap(),
+      NgCommand::List { limit: None }
+    );
+  }
+
+  #[test]
+  fn list_result() {
+    let result = NgCmdResult::List {
+      result: "ok".to_string(),
+      error_reason: None,
+      warning: None,
+      calls: Some(vec!["call-1".to_string(), "call-2".to_string()]),
+    };
+    assert_eq!(result.to_str(), "d5:callsl6:call-16:call-2e6:result2:oke");
+  }
+
+  #[test]
+  fn query_result() {
+    let mut tags = BTreeMap::new();
+    tags.insert(
+      "460d801e".to_string(),
+      NgLegStats {
+        tag: "460d801e".to_string(),
+        created: 1700000000,
+        local_address: "1.1.1.1:10000".to_string(),
+        remote_address: "2.2.2.2:4000".to_string(),
+        ingress: NgTrafficStats {
+          packets: 2,
+          bytes: 344,
+          errors: 0,
+        },
+        egress: NgTrafficStats::default(),
+      },
+    );
+    let result = NgCmdResult::Query {
+      result: "ok".to_string(),
+      error_reason: None,
+      warning: None,
+      created: Some(1700000000),
+      tags: Some(tags),
+    };
+    assert_eq!(
+      result.to_str(),
+      "d7:createdi1700000000e6:result2:ok4:tagsd8:460d801ed7:createdi1700000000e6:egressd5:bytesi0e6:errorsi0e7:packetsi0ee7:ingressd5:bytesi344e6:errorsi0e7:packetsi2ee13:local-address13:1.1.1.1:1000014:remote-address12:2.2.2.2:40003:tag8:460d801eeee"
+    );
+  }
+
+  #[test]
+  fn error_result() {
+    let mut result = NgCmdResult::error("Unknown call-id");
+    result.set_warning("Unknown flag 'x'".to_string());
+    assert_eq!(
+      result.to_str(),
+      "d12:error-reason15:Unknown call-id6:result5:error7:warning16:Unknown flag 'x'e"
+    );
+  }
+
+  #[test]
+  fn decode_error_reply() {
+    let err = NgRequest::from_str("5323_1 d7:command7:unknowne").unwrap_err();
+    assert_eq!(err.id, "5323_1");
+    assert!(err.response().to_str().starts_with("5323_1 d12:error-reason"));
+  }
 }
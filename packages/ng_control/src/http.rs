@@ -0,0 +1,179 @@
+use std::{
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+};
+
+use axum::{
+  extract::{
+    rejection::JsonRejection,
+    ws::{Message, WebSocket, WebSocketUpgrade},
+    ConnectInfo, State,
+  },
+  response::Response,
+  routing::{get, post},
+  Json, Router,
+};
+use log::{debug, error};
+use media::{MediaRpcRequest, MediaRpcResponse, Rpc};
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::{
+  commands::{NgCmdResult, NgCommand, NgRequest},
+  server::NgControlServer,
+};
+
+//websocket subprotocol of the ng messages, same as rtpengine
+const WS_PROTOCOL: &str = "ng.rtpengine.com";
+
+#[derive(Clone)]
+struct HttpState {
+  rpc_sender: mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+  //http requests have no cookie, each one gets a sequence number instead
+  seq: Arc<AtomicU64>,
+}
+
+// POST /ng takes a json command and returns the json result,
+// GET /ws upgrades to a websocket carrying ng messages: "<cookie> <bencode>"
+pub struct NgHttpControlServer {
+  //taken by the server once it runs
+  listener: Option<TcpListener>,
+  rpc_sender: mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+}
+
+impl NgHttpControlServer {
+  // the listener is bound before the server runs, so a used address fails the startup
+  pub async fn bind(
+    addr: SocketAddr,
+    sender: mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+  ) -> std::io::Result<Self> {
+    let listener = TcpListener::bind(addr).await?;
+    debug!("start ng http control server at: {}", addr);
+    Ok(Self {
+      listener: Some(listener),
+      rpc_sender: sender,
+    })
+  }
+
+  pub async fn process(&mut self) {
+    let listener = match self.listener.take() {
+      Some(listener) => listener,
+      None => return,
+    };
+    let app = Router::new()
+      .route("/ng", post(Self::on_json))
+      .route("/ws", get(Self::on_websocket))
+      .with_state(HttpState {
+        rpc_sender: self.rpc_sender.clone(),
+        seq: Arc::new(AtomicU64::new(0)),
+      });
+    if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
+      error!("ng http control server stopped: {}", e);
+    }
+  }
+
+  async fn on_json(
+    State(state): State<HttpState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    command: Result<Json<NgCommand>, JsonRejection>,
+  ) -> Json<NgCmdResult> {
+    let command = match command {
+      Ok(Json(command)) => command,
+      Err(e) => {
+        return Json(NgCmdResult::error(&format!(
+          "Failed to decode command: {}",
+          e.body_text()
+        )))
+      }
+    };
+    let req = NgRequest {
+      id: format!("http-{}", state.seq.fetch_add(1, Ordering::Relaxed)),
+      command,
+    };
+    Json(NgControlServer::execute(&state.rpc_sender, addr, req).await.result)
+  }
+
+  async fn on_websocket(
+    State(state): State<HttpState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ws: WebSocketUpgrade,
+  ) -> Response {
+    ws.protocols([WS_PROTOCOL])
+      .on_upgrade(move |socket| Self::process_websocket(socket, addr, state.rpc_sender))
+  }
+
+  async fn process_websocket(
+    mut socket: WebSocket,
+    addr: SocketAddr,
+    rpc_sender: mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+  ) {
+    debug!("ng websocket from {}", addr);
+    let (tx, mut rx) = mpsc::channel::<String>(100);
+    loop {
+      tokio::select! {
+        msg = socket.recv() => {
+          let msg = match msg {
+            Some(Ok(Message::Text(msg))) => msg,
+            Some(Ok(Message::Binary(msg))) => String::from_utf8_lossy(&msg).to_string(),
+            Some(Ok(Message::Close(_))) | None => break,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => {
+              error!("error when read ng websocket {}: {}", addr, e);
+              break;
+            }
+          };
+          debug!("received websocket msg: {}", msg);
+          NgControlServer::dispatch(&rpc_sender, addr, &msg, &tx).await;
+        }
+        Some(msg) = rx.recv() => {
+          if let Err(e) = socket.send(Message::Text(msg)).await {
+            error!("error when write ng websocket {}: {}", addr, e);
+            break;
+          }
+        }
+      }
+    }
+    debug!("ng websocket from {} closed", addr);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::commands::{NgCmdResult, NgCommand};
+
+  #[test]
+  fn json_command() {
+    let command: NgCommand =
+      serde_json::from_str(r#"{"command": "delete", "call-id": "call-1", "from-tag": "460d801e", "delete-delay": 30}"#)
+        .unwrap();
+    assert_eq!(
+      command,
+      NgCommand::Delete {
+        call_id: "call-1".to_string(),
+        from_tag: "460d801e".to_string(),
+        to_tag: None,
+        delete_delay: Some(30),
+      }
+    );
+  }
+
+  #[test]
+  fn json_result() {
+    let result = NgCmdResult::List {
+      result: "ok".to_string(),
+      error_reason: None,
+      warning: None,
+      calls: Some(vec!["call-1".to_string()]),
+    };
+    assert_eq!(
+      serde_json::to_string(&result).unwrap(),
+      r#"{"result":"ok","calls":["call-1"]}"#
+    );
+    assert_eq!(
+      serde_json::to_string(&NgCmdResult::error("Unknown call-id")).unwrap(),
+      r#"{"result":"error","error-reason":"Unknown call-id"}"#
+    );
+  }
+}
@@ -1,5 +1,10 @@
+mod cache;
 mod commands;
+mod http;
 mod server;
+mod tcp;
 
 pub use commands::*;
+pub use http::*;
 pub use server::*;
+pub use tcp::*;
@@ -1,61 +1,81 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+  net::{IpAddr, SocketAddr},
+  time::Instant,
+};
 
 use log::{debug, error};
 use media::{MediaRpcRequest, MediaRpcResponse, Rpc};
 use tokio::net::UdpSocket;
 
-use crate::commands::{NgCmdResult, NgCommand, NgRequest, NgResponse};
+use crate::cache::{CacheLookup, ResponseCache, RESPONSE_CACHE_TTL};
+use crate::commands::{NgCmdResult, NgCommand, NgLegStats, NgRequest, NgResponse, NgSdpOptions, NgTrafficStats};
 
 pub enum NgControlMsg {
-  Request(NgRequest),
-  Response(NgResponse),
+  Request(SocketAddr, Box<NgRequest>),
+  Response(SocketAddr, NgResponse),
 }
 
 pub struct NgControlServer {
-  addr: String,
+  socket: UdpSocket,
   rpc_sender: tokio::sync::mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
 }
 
 impl NgControlServer {
-  pub fn new(addr: String, sender: tokio::sync::mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>) -> Self {
-    Self {
-      addr,
+  // the socket is bound before the server runs, so a used address fails the startup
+  pub async fn bind(
+    addr: SocketAddr,
+    sender: tokio::sync::mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+  ) -> std::io::Result<Self> {
+    let socket = UdpSocket::bind(addr).await?;
+    debug!("start ng control server at: {}", addr);
+    Ok(Self {
+      socket,
       rpc_sender: sender,
-    }
+    })
   }
 
   pub async fn process(&mut self) {
-    debug!("start ng control server at: {}", self.addr);
-    let socket = UdpSocket::bind(self.addr.clone()).await.unwrap();
-    let mut buf = vec![0; 1400];
-    let mut request_mapper: HashMap<String, SocketAddr> = HashMap::new();
+    let socket = &self.socket;
+    // the largest udp payload, big sdps with many ice candidates don't fit in a mtu
+    let mut buf = vec![0; 65535];
+    let mut cache = ResponseCache::new(RESPONSE_CACHE_TTL);
     let (tx, mut rx) = tokio::sync::mpsc::channel::<NgControlMsg>(100);
     loop {
       tokio::select! {
           Ok((len, addr)) = socket.recv_from(&mut buf) => {
-            let msg = std::str::from_utf8(&buf[..len]).unwrap().to_string();
+            let msg = String::from_utf8_lossy(&buf[..len]).to_string();
             debug!("received msg: {}", msg);
             let cmd = NgRequest::from_str(&msg);
             match cmd {
-                Some(cmd) => {
-                    request_mapper.insert(cmd.id.clone(), addr);
-                    tx.send(NgControlMsg::Request(cmd)).await.unwrap();
-                }
-                None => {
-                    error!("error when parser to ng request");
+                Ok(cmd) => match cache.lookup(Instant::now(), addr, &cmd.id) {
+                    CacheLookup::New => {
+                        if let Err(e) = tx.send(NgControlMsg::Request(addr, Box::new(cmd))).await {
+                            error!("error when queue ng request: {}", e);
+                        }
+                    }
+                    CacheLookup::Pending => {
+                        debug!("drop retransmission of {} from {}, still processing", cmd.id, addr);
+                    }
+                    CacheLookup::Done(msg) => {
+                        debug!("resend cached reply of {} to {}", cmd.id, addr);
+                        Self::send_reply(socket, addr, &msg).await;
+                    }
+                },
+                Err(e) => {
+                    error!("error when parser to ng request: {}", e.reason);
+                    Self::send_reply(socket, addr, &e.response().to_str()).await;
                 }
             }
           }
           Some(msg) = rx.recv() => {
             match msg {
-                NgControlMsg::Request(req) => {
-                    self.handle_ng_request(req, tx.clone());
+                NgControlMsg::Request(addr, req) => {
+                    self.handle_ng_request(addr, *req, tx.clone());
                 }
-                NgControlMsg::Response(res) => {
-                    if let Some(addr) = request_mapper.remove(&res.id) {
-                        let msg = res.to_str();
-                        socket.send_to(msg.as_bytes(), addr).await.unwrap();
-                    }
+                NgControlMsg::Response(addr, res) => {
+                    let msg = res.to_str();
+                    cache.store(Instant::now(), addr, &res.id, &msg);
+                    Self::send_reply(socket, addr, &msg).await;
                 }
             }
           }
@@ -66,42 +86,152 @@ impl NgControlServer {
     }
   }
 
-  pub fn handle_ng_request(&self, req: NgRequest, tx: tokio::sync::mpsc::Sender<NgControlMsg>) {
+  // the source of a request may be an address the kernel refuses to send to, e.g. a spoofed broadcast
+  async fn send_reply(socket: &UdpSocket, addr: SocketAddr, msg: &str) {
+    if let Err(e) = socket.send_to(msg.as_bytes(), addr).await {
+      error!("error when send ng reply to {}: {}", addr, e);
+    }
+  }
+
+  // the cookie is only unique for its client, the media rpc gets an id unique for the engine
+  pub fn handle_ng_request(&self, addr: SocketAddr, req: NgRequest, tx: tokio::sync::mpsc::Sender<NgControlMsg>) {
     let tx = tx.clone();
     let rpc_sender = self.rpc_sender.clone();
     tokio::spawn(async move {
-      let rpc_req = Self::rpc_request_from_ng(req);
-      let (rpc, rx) = Rpc::<media::MediaRpcRequest, media::MediaRpcResponse>::new(rpc_req);
-      rpc_sender.send(rpc).await.unwrap();
-      let res = rx.await.unwrap();
-      let ng_res = Self::ng_response_from_rpc(res);
-      tx.send(NgControlMsg::Response(ng_res)).await.unwrap();
+      let ng_res = Self::execute(&rpc_sender, addr, req).await;
+      if let Err(e) = tx.send(NgControlMsg::Response(addr, ng_res)).await {
+        error!("error when queue ng reply to {}: {}", addr, e);
+      }
     });
   }
 
-  pub fn rpc_request_from_ng(ng_request: NgRequest) -> media::MediaRpcRequest {
-    match ng_request.command {
+  // Used by the stream transports: the requests of a connection run concurrently and their encoded
+  // replies are queued on `tx` as they come, for the connection loop to write.
+  pub async fn dispatch(
+    rpc_sender: &tokio::sync::mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+    addr: SocketAddr,
+    msg: &str,
+    tx: &tokio::sync::mpsc::Sender<String>,
+  ) {
+    match NgRequest::from_str(msg) {
+      Ok(req) => {
+        let tx = tx.clone();
+        let rpc_sender = rpc_sender.clone();
+        tokio::spawn(async move {
+          let res = Self::execute(&rpc_sender, addr, req).await;
+          tx.send(res.to_str()).await.ok();
+        });
+      }
+      Err(e) => {
+        error!("error when parser to ng request: {}", e.reason);
+        tx.send(e.response().to_str()).await.ok();
+      }
+    }
+  }
+
+  // runs the command on the media workers, shared by every ng transport
+  pub async fn execute(
+    rpc_sender: &tokio::sync::mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+    addr: SocketAddr,
+    req: NgRequest,
+  ) -> NgResponse {
+    let cookie = req.id.clone();
+    let (mut rpc_req, warnings) = Self::rpc_request_from_ng(req);
+    rpc_req.id = format!("{}/{}", addr, cookie);
+    let (rpc, rx) = Rpc::<media::MediaRpcRequest, media::MediaRpcResponse>::new(rpc_req);
+    let mut result = match rpc_sender.send(rpc).await {
+      Ok(()) => match rx.await {
+        Ok(res) => Self::ng_response_from_rpc(res).result,
+        Err(_) => NgCmdResult::error("Media engine stopped"),
+      },
+      Err(_) => NgCmdResult::error("Media engine stopped"),
+    };
+    if !warnings.is_empty() {
+      result.set_warning(warnings.join(", "));
+    }
+    NgResponse { id: cookie, result }
+  }
+
+  // the second value lists the options that were ignored
+  pub fn rpc_request_from_ng(ng_request: NgRequest) -> (media::MediaRpcRequest, Vec<String>) {
+    let mut warnings = vec![];
+    let req = match ng_request.command {
       NgCommand::Offer {
-        sdp, call_id, from_tag, ..
+        sdp,
+        call_id,
+        from_tag,
+        to_tag,
+        options,
       } => media::MediaRpcRequest {
         id: ng_request.id,
-        cmd: media::MediaRpcCmd::Call(call_id, from_tag, sdp),
+        cmd: media::MediaRpcCmd::Offer(media::SdpExchange {
+          call_id,
+          from_tag,
+          to_tag,
+          sdp,
+          rtcp_mux: Self::rtcp_mux_flags(&options.rtcp_mux, &mut warnings),
+          address_family: Self::address_family(&options.address_family, &mut warnings),
+          from_interface: options
+            .direction
+            .as_ref()
+            .and_then(|direction| direction.first().cloned()),
+          to_interface: options.interface.clone().or(
+            options
+              .direction
+              .as_ref()
+              .and_then(|direction| direction.get(1).cloned()),
+          ),
+          options: Self::sdp_options(&options, &mut warnings),
+        }),
       },
       NgCommand::Answer {
-        sdp, call_id, to_tag, ..
+        sdp,
+        call_id,
+        from_tag,
+        to_tag,
+        options,
+      } => media::MediaRpcRequest {
+        id: ng_request.id,
+        cmd: media::MediaRpcCmd::Answer(media::SdpExchange {
+          call_id,
+          from_tag,
+          to_tag: Some(to_tag),
+          sdp,
+          rtcp_mux: Self::rtcp_mux_flags(&options.rtcp_mux, &mut warnings),
+          address_family: None,
+          from_interface: None,
+          to_interface: None,
+          options: Self::sdp_options(&options, &mut warnings),
+        }),
+      },
+      NgCommand::Delete {
+        call_id,
+        from_tag,
+        to_tag,
+        delete_delay,
       } => media::MediaRpcRequest {
         id: ng_request.id,
-        cmd: media::MediaRpcCmd::Call(call_id, to_tag, sdp),
+        cmd: media::MediaRpcCmd::End(media::EndRequest {
+          call_id,
+          from_tag: Some(from_tag),
+          to_tag,
+          delay: delete_delay,
+        }),
       },
-      NgCommand::Delete { call_id, .. } => media::MediaRpcRequest {
+      NgCommand::Query { call_id, .. } => media::MediaRpcRequest {
         id: ng_request.id,
-        cmd: media::MediaRpcCmd::End(call_id),
+        cmd: media::MediaRpcCmd::Query(call_id),
+      },
+      NgCommand::List { limit } => media::MediaRpcRequest {
+        id: ng_request.id,
+        cmd: media::MediaRpcCmd::List(limit.map(|limit| limit as usize)),
       },
       NgCommand::Ping {} => media::MediaRpcRequest {
         id: ng_request.id,
         cmd: media::MediaRpcCmd::Ping,
       },
-    }
+    };
+    (req, warnings)
   }
 
   pub fn ng_response_from_rpc(rpc_response: media::MediaRpcResponse) -> NgResponse {
@@ -111,13 +241,24 @@ impl NgControlServer {
         result: NgCmdResult::Pong {
           result: "pong".to_string(),
           error_reason: None,
+          warning: None,
         },
       },
-      media::MediaRpcResult::Call(sdp) => NgResponse {
+      media::MediaRpcResult::Offer(sdp) => NgResponse {
         id: rpc_response.id,
         result: NgCmdResult::Offer {
           result: "ok".to_string(),
           error_reason: None,
+          warning: None,
+          sdp: Some(sdp),
+        },
+      },
+      media::MediaRpcResult::Answer(sdp) => NgResponse {
+        id: rpc_response.id,
+        result: NgCmdResult::Answer {
+          result: "ok".to_string(),
+          error_reason: None,
+          warning: None,
           sdp: Some(sdp),
         },
       },
@@ -126,15 +267,342 @@ impl NgControlServer {
         result: NgCmdResult::Delete {
           result: "ok".to_string(),
           error_reason: None,
+          warning: None,
         },
       },
-      media::MediaRpcResult::Error(reason) => NgResponse {
+      media::MediaRpcResult::Query(stats) => NgResponse {
         id: rpc_response.id,
-        result: NgCmdResult::Pong {
-          result: "error".to_string(),
-          error_reason: Some(reason),
+        result: NgCmdResult::Query {
+          result: "ok".to_string(),
+          error_reason: None,
+          warning: None,
+          created: Some(stats.created),
+          tags: Some(
+            stats
+              .legs
+              .into_iter()
+              .map(|leg| {
+                (
+                  leg.tag.clone(),
+                  NgLegStats {
+                    tag: leg.tag,
+                    created: leg.created,
+                    local_address: leg.local_addr,
+                    remote_address: leg.remote_addr,
+                    ingress: Self::ng_traffic_stats(leg.ingress),
+                    egress: Self::ng_traffic_stats(leg.egress),
+                  },
+                )
+              })
+              .collect(),
+          ),
+        },
+      },
+      media::MediaRpcResult::List(calls) => NgResponse {
+        id: rpc_response.id,
+        result: NgCmdResult::List {
+          result: "ok".to_string(),
+          error_reason: None,
+          warning: None,
+          calls: Some(calls),
         },
       },
+      media::MediaRpcResult::Error(reason) => NgResponse {
+        id: rpc_response.id,
+        result: NgCmdResult::error(&reason),
+      },
     }
   }
+
+  fn ng_traffic_stats(counters: media::TrafficCounters) -> NgTrafficStats {
+    NgTrafficStats {
+      packets: counters.packets,
+      bytes: counters.bytes,
+      errors: counters.errors,
+    }
+  }
+
+  // unknown values are ignored and reported as warnings
+  fn unknown<T>(warnings: &mut Vec<String>, option: &str, value: &str) -> Option<T> {
+    warnings.push(format!("Unknown {} '{}'", option, value));
+    None
+  }
+
+  fn rtcp_mux_flags(flags: &Option<Vec<String>>, warnings: &mut Vec<String>) -> Vec<media::RtcpMux> {
+    flags
+      .iter()
+      .flatten()
+      .filter_map(|flag| match flag.as_str() {
+        "offer" => Some(media::RtcpMux::Offer),
+        "accept" => Some(media::RtcpMux::Accept),
+        "demux" => Some(media::RtcpMux::Demux),
+        "reject" => Some(media::RtcpMux::Reject),
+        _ => Self::unknown(warnings, "rtcp-mux flag", flag),
+      })
+      .collect()
+  }
+
+  fn address_family(family: &Option<String>, warnings: &mut Vec<String>) -> Option<media::AddressFamily> {
+    match family.as_deref()? {
+      "IP4" => Some(media::AddressFamily::Ipv4),
+      "IP6" => Some(media::AddressFamily::Ipv6),
+      family => Self::unknown(warnings, "address family", family),
+    }
+  }
+
+  fn ip_addr(addr: Option<&String>, option: &str, warnings: &mut Vec<String>) -> Option<IpAddr> {
+    let addr = addr?;
+    addr.parse().ok().or_else(|| Self::unknown(warnings, option, addr))
+  }
+
+  pub fn sdp_options(options: &NgSdpOptions, warnings: &mut Vec<String>) -> media::SdpOptions {
+    let list = |values: &Option<Vec<String>>| values.iter().flatten().cloned().collect::<Vec<_>>();
+    let codec = options.codec.clone().unwrap_or_default();
+    let sdp_options = media::SdpOptions {
+      flags: Self::sdp_flags(&options.flags, warnings),
+      replace: list(&options.replace)
+        .iter()
+        .filter_map(|value| match value.to_lowercase().replace('_', "-").as_str() {
+          "origin" => Some(media::Replace::Origin),
+          "session-connection" | "session connection" => Some(media::Replace::SessionConnection),
+          "sdp-version" => Some(media::Replace::SdpVersion),
+          "username" => Some(media::Replace::Username),
+          "session-name" => Some(media::Replace::SessionName),
+          "zero-address" => Some(media::Replace::ZeroAddress),
+          _ => Self::unknown(warnings, "replace flag", value),
+        })
+        .collect(),
+      ice: options
+        .ice
+        .as_deref()
+        .and_then(|ice| match ice.to_lowercase().as_str() {
+          "remove" => Some(media::IceOption::Remove),
+          "force" => Some(media::IceOption::Force),
+          "default" => Some(media::IceOption::Default),
+          "force-relay" => Some(media::IceOption::ForceRelay),
+          "optional" => Some(media::IceOption::Optional),
+          _ => Self::unknown(warnings, "ICE option", ice),
+        }),
+      transport_protocol: options.transport_protocol.as_deref().and_then(|protocol| {
+        match protocol.to_uppercase().as_str() {
+          "RTP/AVP" => Some(media::TransportProtocol::RtpAvp),
+          "RTP/SAVP" => Some(media::TransportProtocol::RtpSavp),
+          "RTP/AVPF" => Some(media::TransportProtocol::RtpAvpf),
+          "RTP/SAVPF" => Some(media::TransportProtocol::RtpSavpf),
+          "UDP/TLS/RTP/SAVP" => Some(media::TransportProtocol::UdpTlsRtpSavp),
+          "UDP/TLS/RTP/SAVPF" => Some(media::TransportProtocol::UdpTlsRtpSavpf),
+          "ACCEPT" => Some(media::TransportProtocol::Accept),
+          _ => Self::unknown(warnings, "transport protocol", protocol),
+        }
+      }),
+      codec: media::CodecOptions {
+        strip: list(&codec.strip),
+        offer: list(&codec.offer),
+        transcode: list(&codec.transcode),
+        mask: list(&codec.mask),
+        accept: list(&codec.accept),
+      },
+      sdes: list(&options.sdes)
+        .into_iter()
+        .filter_map(|value| match value.as_str() {
+          "off" | "no" | "disable" => Some(media::SdesOption::Off),
+          _ => match (value.strip_prefix("no-"), value.strip_prefix("only-")) {
+            (Some(suite), _) => Some(media::SdesOption::No(suite.to_string())),
+            (_, Some(suite)) => Some(media::SdesOption::Only(suite.to_string())),
+            _ => Self::unknown(warnings, "SDES flag", &value),
+          },
+        })
+        .collect(),
+      dtls: options
+        .dtls
+        .as_deref()
+        .and_then(|dtls| match dtls.to_lowercase().as_str() {
+          "off" | "no" | "disable" => Some(media::DtlsOption::Off),
+          "passive" => Some(media::DtlsOption::Passive),
+          "active" => Some(media::DtlsOption::Active),
+          _ => Self::unknown(warnings, "DTLS option", dtls),
+        }),
+      label: options.label.clone(),
+      via_branch: options.via_branch.clone(),
+      received_from: Self::ip_addr(
+        options
+          .received_from
+          .as_ref()
+          .and_then(|received_from| received_from.get(1)),
+        "received-from address",
+        warnings,
+      ),
+      media_address: Self::ip_addr(options.media_address.as_ref(), "media address", warnings),
+    };
+    Self::unsupported_options(options, &sdp_options, warnings);
+    sdp_options
+  }
+
+  // The media layer takes these options but does not act on them yet, the client is told they had
+  // no effect. Unknown values were already reported.
+  fn unsupported_options(options: &NgSdpOptions, sdp_options: &media::SdpOptions, warnings: &mut Vec<String>) {
+    let mut unsupported = |option: &str, value: &str| warnings.push(format!("Unsupported {} '{}'", option, value));
+    let flags = &sdp_options.flags;
+    for (set, flag) in [
+      (flags.trust_address, "trust-address"),
+      (flags.symmetric, "symmetric"),
+      (flags.asymmetric, "asymmetric"),
+      (flags.strict_source, "strict-source"),
+      (flags.media_handover, "media-handover"),
+      (flags.port_latching, "port-latching"),
+      (flags.record_call, "record-call"),
+      (flags.loop_protect, "loop-protect"),
+      (flags.sip_source_address, "SIP-source-address"),
+      (flags.no_rtcp_attribute, "no-rtcp-attribute"),
+      (flags.full_rtcp_attribute, "full-rtcp-attribute"),
+      (flags.original_sendrecv, "original-sendrecv"),
+    ] {
+      if set {
+        unsupported("flag", flag);
+      }
+    }
+    if sdp_options.replace.contains(&media::Replace::ZeroAddress) {
+      unsupported("replace flag", "zero-address");
+    }
+    // the ice attributes are always removed
+    if let (Some(ice), Some(value)) = (sdp_options.ice, &options.ice) {
+      if ice != media::IceOption::Remove {
+        unsupported("ICE option", value);
+      }
+    }
+    if let (Some(_), Some(value)) = (sdp_options.transport_protocol, &options.transport_protocol) {
+      unsupported("transport protocol", value);
+    }
+    // strip and mask are applied, the others need transcoding
+    let codec = &sdp_options.codec;
+    for (option, codecs) in [
+      ("codec-offer", &codec.offer),
+      ("codec-transcode", &codec.transcode),
+      ("codec-accept", &codec.accept),
+    ] {
+      for value in codecs {
+        unsupported(option, value);
+      }
+    }
+    for sdes in sdp_options.sdes.iter() {
+      match sdes {
+        media::SdesOption::Off => unsupported("SDES flag", "off"),
+        media::SdesOption::No(suite) => unsupported("SDES flag", &format!("no-{}", suite)),
+        media::SdesOption::Only(suite) => unsupported("SDES flag", &format!("only-{}", suite)),
+      }
+    }
+    if let (Some(_), Some(value)) = (sdp_options.dtls, &options.dtls) {
+      unsupported("DTLS option", value);
+    }
+    if let Some(addr) = sdp_options.media_address {
+      unsupported("media address", &addr.to_string());
+    }
+    if let Some(addr) = sdp_options.received_from {
+      unsupported("received-from address", &addr.to_string());
+    }
+  }
+
+  fn sdp_flags(flags: &Option<Vec<String>>, warnings: &mut Vec<String>) -> media::SdpFlags {
+    let mut sdp_flags = media::SdpFlags::default();
+    for flag in flags.iter().flatten() {
+      match flag.to_lowercase().replace('_', "-").as_str() {
+        "trust-address" => sdp_flags.trust_address = true,
+        "symmetric" => sdp_flags.symmetric = true,
+        "asymmetric" => sdp_flags.asymmetric = true,
+        "strict-source" => sdp_flags.strict_source = true,
+        "media-handover" => sdp_flags.media_handover = true,
+        "port-latching" => sdp_flags.port_latching = true,
+        "record-call" => sdp_flags.record_call = true,
+        "loop-protect" => sdp_flags.loop_protect = true,
+        "sip-source-address" => sdp_flags.sip_source_address = true,
+        "no-rtcp-attribute" => sdp_flags.no_rtcp_attribute = true,
+        "full-rtcp-attribute" => sdp_flags.full_rtcp_attribute = true,
+        "original-sendrecv" => sdp_flags.original_sendrecv = true,
+        _ => warnings.push(format!("Unknown flag '{}'", flag)),
+      }
+    }
+    sdp_flags
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::commands::{NgCodecOptions, NgSdpOptions};
+
+  use super::NgControlServer;
+
+  #[test]
+  fn typed_sdp_options() {
+    let mut warnings = vec![];
+    let options = NgControlServer::sdp_options(
+      &NgSdpOptions {
+        flags: Some(vec![
+          "trust-address".to_string(),
+          "SIP-source-address".to_string(),
+          "unknown".to_string(),
+        ]),
+        replace: Some(vec!["origin".to_string(), "session connection".to_string()]),
+        ice: Some("force-relay".to_string()),
+        transport_protocol: Some("UDP/TLS/RTP/SAVPF".to_string()),
+        codec: Some(NgCodecOptions {
+          strip: Some(vec!["PCMA".to_string()]),
+          transcode: Some(vec!["opus".to_string()]),
+          ..Default::default()
+        }),
+        sdes: Some(vec!["no-AES_CM_128_HMAC_SHA1_32".to_string()]),
+        dtls: Some("passive".to_string()),
+        received_from: Some(vec!["IP4".to_string(), "192.0.2.2".to_string()]),
+        media_address: Some("not an address".to_string()),
+        ..Default::default()
+      },
+      &mut warnings,
+    );
+    assert!(options.flags.trust_address && options.flags.sip_source_address && !options.flags.symmetric);
+    assert_eq!(
+      options.replace,
+      vec![media::Replace::Origin, media::Replace::SessionConnection]
+    );
+    assert_eq!(options.ice, Some(media::IceOption::ForceRelay));
+    assert_eq!(
+      options.transport_protocol,
+      Some(media::TransportProtocol::UdpTlsRtpSavpf)
+    );
+    assert_eq!(options.codec.strip, vec!["PCMA".to_string()]);
+    assert_eq!(
+      options.sdes,
+      vec![media::SdesOption::No("AES_CM_128_HMAC_SHA1_32".to_string())]
+    );
+    assert_eq!(options.dtls, Some(media::DtlsOption::Passive));
+    assert_eq!(options.received_from, Some("192.0.2.2".parse().unwrap()));
+    assert_eq!(options.media_address, None);
+    assert_eq!(
+      warnings,
+      vec![
+        "Unknown flag 'unknown'".to_string(),
+        "Unknown media address 'not an address'".to_string(),
+        "Unsupported flag 'trust-address'".to_string(),
+        "Unsupported flag 'SIP-source-address'".to_string(),
+        "Unsupported ICE option 'force-relay'".to_string(),
+        "Unsupported transport protocol 'UDP/TLS/RTP/SAVPF'".to_string(),
+        "Unsupported codec-transcode 'opus'".to_string(),
+        "Unsupported SDES flag 'no-AES_CM_128_HMAC_SHA1_32'".to_string(),
+        "Unsupported DTLS option 'passive'".to_string(),
+        "Unsupported received-from address '192.0.2.2'".to_string(),
+      ]
+    );
+  }
+
+  #[test]
+  fn supported_sdp_options() {
+    let mut warnings = vec![];
+    NgControlServer::sdp_options(
+      &NgSdpOptions {
+        replace: Some(vec!["origin".to_string(), "sdp-version".to_string()]),
+        ice: Some("remove".to_string()),
+        ..Default::default()
+      },
+      &mut warnings,
+    );
+    assert!(warnings.is_empty());
+  }
 }
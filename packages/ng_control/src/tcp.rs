@@ -0,0 +1,169 @@
+use std::net::SocketAddr;
+
+use log::{debug, error};
+use media::{MediaRpcRequest, MediaRpcResponse, Rpc};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  sync::mpsc,
+};
+
+use crate::server::NgControlServer;
+
+//netstrings longer than this are rejected and the connection is closed
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+// ng over tcp, each message is a netstring: "<len>:<cookie> <bencode>,"
+pub struct NgTcpControlServer {
+  listener: TcpListener,
+  rpc_sender: mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+}
+
+impl NgTcpControlServer {
+  // the listener is bound before the server runs, so a used address fails the startup
+  pub async fn bind(
+    addr: SocketAddr,
+    sender: mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+  ) -> std::io::Result<Self> {
+    let listener = TcpListener::bind(addr).await?;
+    debug!("start ng tcp control server at: {}", addr);
+    Ok(Self {
+      listener,
+      rpc_sender: sender,
+    })
+  }
+
+  pub async fn process(&mut self) {
+    loop {
+      match self.listener.accept().await {
+        Ok((stream, addr)) => {
+          debug!("ng tcp connection from {}", addr);
+          tokio::spawn(Self::process_connection(stream, addr, self.rpc_sender.clone()));
+        }
+        Err(e) => {
+          error!("error when accept ng tcp connection {}", e);
+        }
+      }
+    }
+  }
+
+  async fn process_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    rpc_sender: mpsc::Sender<Rpc<MediaRpcRequest, MediaRpcResponse>>,
+  ) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::channel::<String>(100);
+    let mut pending = Vec::new();
+    let mut buf = vec![0; 65535];
+    loop {
+      tokio::select! {
+        res = reader.read(&mut buf) => {
+          let len = match res {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => {
+              error!("error when read ng tcp connection {}: {}", addr, e);
+              break;
+            }
+          };
+          pending.extend_from_slice(&buf[..len]);
+          loop {
+            let msg = match decode_netstring(&mut pending) {
+              Ok(Some(msg)) => msg,
+              Ok(None) => break,
+              Err(e) => {
+                error!("invalid netstring from {}: {}", addr, e);
+                return;
+              }
+            };
+            let msg = String::from_utf8_lossy(&msg).to_string();
+            debug!("received tcp msg: {}", msg);
+            NgControlServer::dispatch(&rpc_sender, addr, &msg, &tx).await;
+          }
+        }
+        Some(msg) = rx.recv() => {
+          if let Err(e) = writer.write_all(encode_netstring(&msg).as_bytes()).await {
+            error!("error when write ng tcp connection {}: {}", addr, e);
+            break;
+          }
+        }
+      }
+    }
+    debug!("ng tcp connection from {} closed", addr);
+  }
+}
+
+pub fn encode_netstring(msg: &str) -> String {
+  format!("{}:{},", msg.len(), msg)
+}
+
+// takes the first complete netstring out of the buffer, None until it is fully received
+pub fn decode_netstring(buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>, String> {
+  let colon = match buf.iter().position(|b| *b == b':') {
+    Some(colon) => colon,
+    None if buf.len() > 20 || !buf.iter().all(u8::is_ascii_digit) => return Err("missing length".to_string()),
+    None => return Ok(None),
+  };
+  let len: usize = std::str::from_utf8(&buf[..colon])
+    .ok()
+    .filter(|len| !len.is_empty() && len.bytes().all(|b| b.is_ascii_digit()))
+    .and_then(|len| len.parse().ok())
+    .ok_or("invalid length".to_string())?;
+  if len > MAX_MESSAGE_LEN {
+    return Err(format!("message of {} bytes is too big", len));
+  }
+  let end = colon + 1 + len;
+  if buf.len() <= end {
+    return Ok(None);
+  }
+  if buf[end] != b',' {
+    return Err("missing trailing comma".to_string());
+  }
+  let msg = buf[colon + 1..end].to_vec();
+  buf.drain(..=end);
+  Ok(Some(msg))
+}
+
+#[cfg(test)]
+mod test {
+  use tokio::sync::mpsc;
+
+  use super::{decode_netstring, encode_netstring, NgTcpControlServer};
+
+  #[tokio::test]
+  async fn bind_used_address() {
+    let (tx, _rx) = mpsc::channel(1);
+    let server = NgTcpControlServer::bind("127.0.0.1:0".parse().unwrap(), tx.clone())
+      .await
+      .expect("bound");
+    let addr = server.listener.local_addr().unwrap();
+    assert!(NgTcpControlServer::bind(addr, tx).await.is_err());
+  }
+
+  #[test]
+  fn netstring_roundtrip() {
+    let msg = "5323_1 d7:command4:pinge";
+    let mut buf = encode_netstring(msg).into_bytes();
+    assert_eq!(decode_netstring(&mut buf), Ok(Some(msg.as_bytes().to_vec())));
+    assert!(buf.is_empty());
+  }
+
+  #[test]
+  fn netstring_partial_and_pipelined() {
+    let mut buf = b"3:abc,2:d".to_vec();
+    assert_eq!(decode_netstring(&mut buf), Ok(Some(b"abc".to_vec())));
+    assert_eq!(decode_netstring(&mut buf), Ok(None));
+    buf.extend_from_slice(b"e,");
+    assert_eq!(decode_netstring(&mut buf), Ok(Some(b"de".to_vec())));
+    assert_eq!(decode_netstring(&mut buf), Ok(None));
+  }
+
+  #[test]
+  fn invalid_netstring() {
+    assert!(decode_netstring(&mut b"3:abcd".to_vec()).is_err());
+    assert!(decode_netstring(&mut b"x:abc,".to_vec()).is_err());
+    assert!(decode_netstring(&mut b"d7:command".to_vec()).is_err());
+    assert!(decode_netstring(&mut b"99999999:".to_vec()).is_err());
+  }
+}